@@ -1,6 +1,8 @@
+use serde::Serialize;
 use serde_json::{Result, Value};
 use sha2::{Digest, Sha256};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 pub fn print_splash() {
     println!(r"    __  ___________  _____  ________  __   _________    ____  ____ ");
@@ -11,10 +13,20 @@ pub fn print_splash() {
 }
 
 pub fn parse_json(p: &PathBuf) -> Result<Value> {
-    let bytes = std::fs::read_to_string(p).unwrap();
+    let bytes = std::fs::read_to_string(p).map_err(serde_json::Error::io)?;
     serde_json::from_str(&bytes)
 }
 
+pub fn write_json(p: &PathBuf, json: &Value) -> std::io::Result<()> {
+    // match the 4 space indent of the hand written json files
+    let mut bytes = vec![];
+    let formatter = serde_json::ser::PrettyFormatter::with_indent(b"    ");
+    let mut ser = serde_json::Serializer::with_formatter(&mut bytes, formatter);
+    json.serialize(&mut ser)?;
+    bytes.push(b'\n');
+    std::fs::write(p, bytes)
+}
+
 pub fn strip_quotes(s: &str) -> String {
     let s = s.to_string();
     // TODO: this doesn't do what the function says it does
@@ -26,14 +38,15 @@ pub fn print_type_of<T>(_: &T) {
     log::info!("{}", std::any::type_name::<T>())
 }
 
-#[allow(dead_code)]
-pub fn file_sha256(path: &str) -> String {
-    // TODO: check file exists
-    // TODO: make better hash format
-    let bytes = std::fs::read(path).unwrap();
+// lowercase hex, same as sha256sum prints
+pub fn file_sha256(path: &Path) -> std::io::Result<String> {
+    Ok(sha256_hex(&std::fs::read(path)?))
+}
+
+pub fn sha256_hex(bytes: &[u8]) -> String {
     let mut hasher = Sha256::new();
     hasher.update(bytes);
-    format!("{:02X?}", hasher.finalize())
+    hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect()
 }
 
 // get the difference in path between p1 and p2, ie:
@@ -41,6 +54,11 @@ pub fn file_sha256(path: &str) -> String {
 // p2:  /a/b/c/d/e/f.txt
 // ret: (d/e, f.txt)
 pub fn path_diff(p1: PathBuf, p2: PathBuf) -> (PathBuf, PathBuf) {
+    // a tracked file compared with itself, there's no folder in between
+    if p1 == p2 {
+        return (PathBuf::new(), PathBuf::from(p1.file_name().unwrap_or_default()));
+    }
+
     let mut p1i = p1.iter().peekable();
     let mut p2i = p2.iter().peekable();
 
@@ -56,7 +74,7 @@ pub fn path_diff(p1: PathBuf, p2: PathBuf) -> (PathBuf, PathBuf) {
     };
 
     let mut folder = PathBuf::new();
-    while longer_iter.peek() != None {
+    while longer_iter.peek().is_some() {
         folder.push(longer_iter.next().unwrap());
     }
 
@@ -64,6 +82,47 @@ pub fn path_diff(p1: PathBuf, p2: PathBuf) -> (PathBuf, PathBuf) {
     (folder, fname)
 }
 
+// a hidden file next to @dst to write it to before renaming it into place. the name is different for every writer, so
+// this process and another one copying to the same file at once can't mix up each other's half written copies
+pub fn temp_path(dst: &Path) -> PathBuf {
+    let mut random = [0u8; 4];
+    if getrandom::getrandom(&mut random).is_err() {
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.subsec_nanos());
+        random = nanos.to_le_bytes();
+    }
+    let suffix: String = random.iter().map(|b| format!("{:02x}", b)).collect();
+    let mut tmp_name = std::ffi::OsString::from(".");
+    tmp_name.push(dst.file_name().unwrap_or_default());
+    tmp_name.push(format!(".{}-{}.memurycard-tmp", std::process::id(), suffix));
+    dst.with_file_name(tmp_name)
+}
+
+// copy to a temporary file next to @dst and rename it into place so @dst is never left half written if the copy is
+// interrupted
+pub fn copy_atomic(src: &Path, dst: &Path) -> std::io::Result<u64> {
+    let tmp = temp_path(dst);
+
+    let copied = match std::fs::copy(src, &tmp) {
+        Ok(copied) => copied,
+        Err(e) => {
+            let _err = std::fs::remove_file(&tmp);
+            return Err(e);
+        }
+    };
+    std::fs::rename(&tmp, dst)?;
+    Ok(copied)
+}
+
+// same as copy_atomic for contents that are already in memory
+pub fn write_atomic(dst: &Path, contents: impl AsRef<[u8]>) -> std::io::Result<()> {
+    let tmp = temp_path(dst);
+    if let Err(e) = std::fs::write(&tmp, contents).and_then(|_| std::fs::rename(&tmp, dst)) {
+        let _err = std::fs::remove_file(&tmp);
+        return Err(e);
+    }
+    Ok(())
+}
+
 pub fn sanitize_slashes(s: &str) -> String {
     #[cfg(target_os = "windows")]
     let s = str::replace(s, "/", r"\");
@@ -73,3 +132,38 @@ pub fn sanitize_slashes(s: &str) -> String {
 
     path_clean::clean(&s)
 }
+
+// where files that only live as long as the process does, like the control socket, are kept. every install has its
+// own, it's the folder memury card runs from that holds its settings and state, so two installs run side by side
+pub fn runtime_dir() -> PathBuf {
+    #[cfg(target_os = "linux")]
+    {
+        if let Ok(dir) = std::env::var("XDG_RUNTIME_DIR") {
+            if !dir.is_empty() {
+                let install = std::env::current_dir().and_then(|d| d.canonicalize()).unwrap_or_default();
+                let id = &sha256_hex(install.as_os_str().as_encoded_bytes())[..12];
+                return PathBuf::from(dir).join("memurycard").join(id);
+            }
+        }
+    }
+    PathBuf::from("scary")
+}
+
+// name of this computer
+pub fn hostname() -> String {
+    #[cfg(unix)]
+    {
+        let mut buf = [0u8; 256];
+        if unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) } == 0 {
+            let len = buf.iter().position(|b| *b == 0).unwrap_or(buf.len());
+            return String::from_utf8_lossy(&buf[..len]).to_string();
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        if let Ok(name) = std::env::var("COMPUTERNAME") {
+            return name;
+        }
+    }
+    "unknown".to_string()
+}
@@ -1,11 +1,166 @@
-pub fn install(_enabled: bool) {
+use crate::linux::service::Startup;
+use std::os::unix::io::AsRawFd;
 
+pub fn install(enabled: bool) {
+    install_in(&Startup::current(), enabled);
 }
 
 pub fn uninstall() {
+    uninstall_in(&Startup::current());
+}
+
+pub fn enable() {
+    set_enabled(&Startup::current(), true);
+}
+
+pub fn disable() {
+    set_enabled(&Startup::current(), false);
+}
+
+fn install_in(startup: &Startup, enabled: bool) {
+    let result = if startup.systemd_available() {
+        startup.write_unit().and_then(|_| startup.set_unit_enabled(enabled))
+    } else {
+        log::info!("systemd isn't running, using an autostart entry");
+        startup.write_desktop(enabled)
+    };
+    if let Err(e) = result {
+        log::error!("could not install: {:?}", e);
+    }
+}
+
+fn uninstall_in(startup: &Startup) {
+    let removed_unit = startup.remove_unit().unwrap_or_else(|e| {
+        log::error!("could not remove {:?}: {:?}", startup.unit_path(), e);
+        false
+    });
+    let removed_desktop = startup.remove_desktop().unwrap_or_else(|e| {
+        log::error!("could not remove {:?}: {:?}", startup.desktop_path(), e);
+        false
+    });
+    if !removed_unit && !removed_desktop {
+        log::info!("memurycard isn't installed");
+    }
+}
+
+fn set_enabled(startup: &Startup, enabled: bool) {
+    let result = if startup.unit_path().exists() {
+        startup.set_unit_enabled(enabled)
+    } else if startup.desktop_path().exists() {
+        startup.set_desktop_enabled(enabled)
+    } else {
+        log::info!("memurycard isn't installed, run with -i first");
+        return;
+    };
+    match result {
+        Ok(_) => log::info!("{} startup", if enabled { "enabled" } else { "disabled" }),
+        Err(e) => log::error!("could not change startup: {:?}", e),
+    }
+}
+
+// classic double fork. the first child starts a new session so it has no controlling terminal and the second child
+// can never get one back. stdin is pointed at /dev/null and stdout/stderr at the log so nothing blocks or is lost
+pub fn send_to_background(log: &std::path::Path) -> bool {
+    unsafe {
+        match libc::fork() {
+            -1 => {
+                log::error!("could not fork: {:?}", std::io::Error::last_os_error());
+                return false;
+            }
+            0 => (),
+            _ => libc::_exit(0),
+        }
+        if libc::setsid() == -1 {
+            log::error!("could not start a new session: {:?}", std::io::Error::last_os_error());
+            libc::_exit(1);
+        }
+        match libc::fork() {
+            -1 => {
+                log::error!("could not fork: {:?}", std::io::Error::last_os_error());
+                libc::_exit(1);
+            }
+            0 => (),
+            _ => libc::_exit(0),
+        }
+    }
 
+    let devnull = std::fs::File::open("/dev/null");
+    let logfile = std::fs::OpenOptions::new().create(true).append(true).open(log);
+    match (devnull, logfile) {
+        (Ok(devnull), Ok(logfile)) => unsafe {
+            libc::dup2(devnull.as_raw_fd(), libc::STDIN_FILENO);
+            libc::dup2(logfile.as_raw_fd(), libc::STDOUT_FILENO);
+            libc::dup2(logfile.as_raw_fd(), libc::STDERR_FILENO);
+        },
+        (Err(e), _) | (_, Err(e)) => log::error!("could not redirect stdio: {:?}", e),
+    }
+    log::info!("running in background as pid {}", std::process::id());
+    true
 }
 
-pub fn send_to_background() {
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn temp_home(name: &str, systemd: bool) -> Startup {
+        let home = std::env::temp_dir().join(format!("memurycard-{}-{}", name, std::process::id()));
+        let _err = std::fs::remove_dir_all(&home);
+        let root = home.join("root");
+        std::fs::create_dir_all(&root).unwrap();
+        if systemd {
+            // a root with run/systemd/system looks booted with systemd
+            std::fs::create_dir_all(root.join("run/systemd/system")).unwrap();
+        }
+        Startup { root, config: home.join(".config") }
+    }
+
+    fn cleanup(startup: &Startup) {
+        let _err = std::fs::remove_dir_all(startup.root.parent().unwrap());
+    }
+
+    fn wants(startup: &Startup) -> PathBuf {
+        startup.config.join("systemd/user/default.target.wants/memurycard.service")
+    }
+
+    #[test]
+    fn unit_install_enable_uninstall() {
+        let startup = temp_home("unit", true);
+        assert!(startup.systemd_available());
+        install_in(&startup, true);
+        let unit = std::fs::read_to_string(startup.unit_path()).unwrap();
+        assert!(unit.contains("WantedBy=default.target"));
+        assert_eq!(std::fs::read_link(wants(&startup)).unwrap(), startup.unit_path());
+        assert!(!startup.desktop_path().exists());
+
+        set_enabled(&startup, false);
+        assert!(wants(&startup).symlink_metadata().is_err());
+        set_enabled(&startup, true);
+        assert!(wants(&startup).symlink_metadata().is_ok());
+
+        uninstall_in(&startup);
+        assert!(!startup.unit_path().exists());
+        assert!(wants(&startup).symlink_metadata().is_err());
+        cleanup(&startup);
+    }
+
+    #[test]
+    fn desktop_install_enable_uninstall() {
+        let startup = temp_home("desktop", false);
+        assert!(!startup.systemd_available());
+        install_in(&startup, true);
+        let desktop = std::fs::read_to_string(startup.desktop_path()).unwrap();
+        assert!(desktop.contains("Hidden=false"));
+        assert!(desktop.contains(" -b"));
+        assert!(!startup.unit_path().exists());
+
+        set_enabled(&startup, false);
+        let desktop = std::fs::read_to_string(startup.desktop_path()).unwrap();
+        assert!(desktop.contains("Hidden=true"));
+        assert!(desktop.contains("X-GNOME-Autostart-enabled=false"));
 
+        uninstall_in(&startup);
+        assert!(!startup.desktop_path().exists());
+        cleanup(&startup);
+    }
 }
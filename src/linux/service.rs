@@ -0,0 +1,168 @@
+// startup entries for linux. systemd user units are used when systemd is running, otherwise an xdg autostart entry is
+// written for the desktop session to launch. everything lives under $XDG_CONFIG_HOME so nothing needs root
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+static UNIT_NAME: &str = "memurycard.service";
+static DESKTOP_NAME: &str = "memurycard.desktop";
+
+pub fn config_home() -> PathBuf {
+    match std::env::var("XDG_CONFIG_HOME") {
+        Ok(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => {
+            let mut home = PathBuf::from(std::env::var("HOME").unwrap_or_default());
+            home.push(".config");
+            home
+        }
+    }
+}
+
+fn exe_paths() -> (PathBuf, PathBuf) {
+    let exe = std::env::current_exe().unwrap();
+    let exedir = exe.parent().unwrap().to_path_buf();
+    (exe, exedir)
+}
+
+fn systemctl(args: &[&str]) {
+    match Command::new("systemctl").arg("--user").args(args).status() {
+        Ok(status) if status.success() => (),
+        Ok(status) => log::warn!("systemctl --user {} exited with {}", args.join(" "), status),
+        Err(e) => log::warn!("could not run systemctl: {:?}", e),
+    }
+}
+
+// where the startup entries go. root and config are parameters so everything can be pointed at a temp folder
+pub struct Startup {
+    pub root: PathBuf,
+    pub config: PathBuf,
+}
+
+impl Startup {
+    pub fn current() -> Startup {
+        Startup { root: PathBuf::from("/"), config: config_home() }
+    }
+
+    // same check as sd_booted(3)
+    pub fn systemd_available(&self) -> bool {
+        self.root.join("run/systemd/system").is_dir()
+    }
+
+    // tell the user manager the units changed. only the real root has a manager to tell
+    fn reload(&self) {
+        if self.root == Path::new("/") && self.systemd_available() {
+            systemctl(&["daemon-reload"]);
+        }
+    }
+
+    pub fn unit_path(&self) -> PathBuf {
+        self.config.join("systemd/user").join(UNIT_NAME)
+    }
+
+    fn unit_wants_path(&self) -> PathBuf {
+        self.config.join("systemd/user/default.target.wants").join(UNIT_NAME)
+    }
+
+    pub fn desktop_path(&self) -> PathBuf {
+        self.config.join("autostart").join(DESKTOP_NAME)
+    }
+
+    // systemd keeps the process in the foreground itself so the unit runs memurycard without -b
+    pub fn write_unit(&self) -> std::io::Result<()> {
+        let (exe, exedir) = exe_paths();
+        let unit = format!(
+"[Unit]
+Description=Memury Card game save sync
+
+[Service]
+Type=simple
+WorkingDirectory={}
+ExecStart=\"{}\"
+Restart=on-failure
+
+[Install]
+WantedBy=default.target
+", exedir.display(), exe.display());
+
+        let path = self.unit_path();
+        std::fs::create_dir_all(path.parent().unwrap())?;
+        std::fs::write(&path, unit)?;
+        log::info!("wrote {:?}", path);
+        self.reload();
+        Ok(())
+    }
+
+    // this is what systemctl enable does for a unit wanted by default.target, doing it by hand means it works without a
+    // user manager running too
+    pub fn set_unit_enabled(&self, enabled: bool) -> std::io::Result<()> {
+        let wants = self.unit_wants_path();
+        if enabled {
+            std::fs::create_dir_all(wants.parent().unwrap())?;
+            if wants.symlink_metadata().is_err() {
+                std::os::unix::fs::symlink(self.unit_path(), &wants)?;
+            }
+        } else if wants.symlink_metadata().is_ok() {
+            std::fs::remove_file(&wants)?;
+        }
+        self.reload();
+        Ok(())
+    }
+
+    pub fn remove_unit(&self) -> std::io::Result<bool> {
+        let path = self.unit_path();
+        if !path.exists() {
+            return Ok(false);
+        }
+        self.set_unit_enabled(false)?;
+        std::fs::remove_file(&path)?;
+        log::info!("removed {:?}", path);
+        self.reload();
+        Ok(true)
+    }
+
+    pub fn write_desktop(&self, enabled: bool) -> std::io::Result<()> {
+        let (exe, exedir) = exe_paths();
+        let desktop = format!(
+"[Desktop Entry]
+Type=Application
+Name=Memury Card
+Comment=Game save sync
+Exec=\"{}\" -b
+Path={}
+Terminal=false
+Hidden={}
+X-GNOME-Autostart-enabled={}
+", exe.display(), exedir.display(), !enabled, enabled);
+
+        let path = self.desktop_path();
+        std::fs::create_dir_all(path.parent().unwrap())?;
+        std::fs::write(&path, desktop)?;
+        log::info!("wrote {:?}", path);
+        Ok(())
+    }
+
+    // Hidden=true is how the autostart spec says to turn an entry off without deleting it
+    pub fn set_desktop_enabled(&self, enabled: bool) -> std::io::Result<()> {
+        let path = self.desktop_path();
+        let desktop = std::fs::read_to_string(&path)?;
+        let desktop: Vec<String> = desktop.lines().map(|line| {
+            if line.starts_with("Hidden=") {
+                format!("Hidden={}", !enabled)
+            } else if line.starts_with("X-GNOME-Autostart-enabled=") {
+                format!("X-GNOME-Autostart-enabled={}", enabled)
+            } else {
+                line.to_string()
+            }
+        }).collect();
+        std::fs::write(&path, desktop.join("\n") + "\n")
+    }
+
+    pub fn remove_desktop(&self) -> std::io::Result<bool> {
+        let path = self.desktop_path();
+        if !path.exists() {
+            return Ok(false);
+        }
+        std::fs::remove_file(&path)?;
+        log::info!("removed {:?}", path);
+        Ok(true)
+    }
+}
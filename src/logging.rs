@@ -0,0 +1,215 @@
+// log setup. everything can be set in settings.json and the level, folder and quiet console can be overridden on the
+// command line:
+// "log_level": "info"                                 level for everything
+// "log_modules": { "memurycard::service": "debug" }   levels for single modules, by log target
+// "log_dir": "logs"                                   where memurycard.log and its archives go
+// "log_rotate": "10 MB" or "daily"                    roll the log over at a size or on a schedule
+// "log_keep": 5                                       how many rolled over logs to keep
+// "log_console": false                                only print warnings and errors to the console
+// "event_log": "events.jsonl"                         where the json record of every sync action goes
+use log::LevelFilter;
+use log4rs::append::console::ConsoleAppender;
+use log4rs::append::rolling_file::policy::compound::roll::fixed_window::FixedWindowRoller;
+use log4rs::append::rolling_file::policy::compound::trigger::size::SizeTrigger;
+use log4rs::append::rolling_file::policy::compound::trigger::time::{
+    TimeTrigger, TimeTriggerConfig, TimeTriggerInterval,
+};
+use log4rs::append::rolling_file::policy::compound::trigger::Trigger;
+use log4rs::append::rolling_file::policy::compound::CompoundPolicy;
+use log4rs::append::rolling_file::RollingFileAppender;
+use log4rs::config::{Appender, Config, Logger, Root};
+use log4rs::encode::pattern::PatternEncoder;
+use log4rs::filter::threshold::ThresholdFilter;
+use serde_json::Value;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+static LOG_NAME: &str = "memurycard";
+const DEFAULT_MAX_SIZE: u64 = 10 * 1024 * 1024;
+const DEFAULT_KEEP: u32 = 5;
+
+pub struct LogOptions {
+    level: LevelFilter,
+    modules: Vec<(String, LevelFilter)>,
+    dir: PathBuf,
+    rotate: Rotate,
+    keep: u32,
+    console: bool,
+    events: Option<PathBuf>,
+}
+
+enum Rotate {
+    Size(u64),
+    Every(TimeTriggerInterval),
+}
+
+// $XDG_STATE_HOME/memurycard/log on linux so an install to a read only folder can still log
+fn default_log_dir() -> PathBuf {
+    #[cfg(target_os = "linux")]
+    {
+        if let Ok(dir) = std::env::var("XDG_STATE_HOME") {
+            if !dir.is_empty() {
+                return PathBuf::from(dir).join("memurycard/log");
+            }
+        }
+        if let Ok(home) = std::env::var("HOME") {
+            return PathBuf::from(home).join(".local/state/memurycard/log");
+        }
+    }
+    PathBuf::from("scary/log")
+}
+
+fn parse_level(s: &str) -> Result<LevelFilter, String> {
+    LevelFilter::from_str(s).map_err(|_| format!("unknown log level \"{}\"", s))
+}
+
+// "10 MB", "512kb", "1048576"
+fn parse_size(s: &str) -> Option<u64> {
+    let s = s.trim().to_lowercase();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (n, unit) = s.split_at(split);
+    let n: u64 = n.parse().ok()?;
+    let mult = match unit.trim() {
+        "" | "b" => 1,
+        "k" | "kb" => 1024,
+        "m" | "mb" => 1024 * 1024,
+        "g" | "gb" => 1024 * 1024 * 1024,
+        _ => return None,
+    };
+    Some(n * mult)
+}
+
+fn parse_rotate(v: &Value) -> Result<Rotate, String> {
+    if let Some(n) = v.as_u64() {
+        return Ok(Rotate::Size(n));
+    }
+    let s = v.as_str().ok_or_else(|| format!("log_rotate should be a size or a schedule, not {}", v))?;
+    let every = match s.trim().to_lowercase().as_str() {
+        "hourly" => Some(TimeTriggerInterval::Hour(1)),
+        "daily" => Some(TimeTriggerInterval::Day(1)),
+        "weekly" => Some(TimeTriggerInterval::Week(1)),
+        "monthly" => Some(TimeTriggerInterval::Month(1)),
+        _ => None,
+    };
+    match every {
+        Some(every) => Ok(Rotate::Every(every)),
+        None => parse_size(s).map(Rotate::Size).ok_or_else(|| format!("could not understand log_rotate \"{}\"", s)),
+    }
+}
+
+impl LogOptions {
+    // settings.json first, then the command line on top. @overrides are either a level for everything or
+    // module=level. problems are returned alongside the options so they can be logged once logging works
+    pub fn load(settings: Option<&Value>, overrides: &[String], dir: Option<PathBuf>, quiet: bool)
+        -> (LogOptions, Vec<String>) {
+        let mut opts = LogOptions {
+            level: LevelFilter::Info,
+            modules: vec![],
+            dir: default_log_dir(),
+            rotate: Rotate::Size(DEFAULT_MAX_SIZE),
+            keep: DEFAULT_KEEP,
+            console: true,
+            events: None,
+        };
+        let mut problems = vec![];
+
+        if let Some(settings) = settings {
+            if let Some(level) = settings["log_level"].as_str() {
+                match parse_level(level) {
+                    Ok(level) => opts.level = level,
+                    Err(e) => problems.push(e),
+                }
+            }
+            if let Some(modules) = settings["log_modules"].as_object() {
+                for (module, level) in modules {
+                    match parse_level(level.as_str().unwrap_or_default()) {
+                        Ok(level) => opts.modules.push((module.clone(), level)),
+                        Err(e) => problems.push(format!("{} for {}", e, module)),
+                    }
+                }
+            }
+            if let Some(dir) = settings["log_dir"].as_str() {
+                opts.dir = PathBuf::from(crate::helper::sanitize_slashes(dir));
+            }
+            if !settings["log_rotate"].is_null() {
+                match parse_rotate(&settings["log_rotate"]) {
+                    Ok(rotate) => opts.rotate = rotate,
+                    Err(e) => problems.push(e),
+                }
+            }
+            if let Some(keep) = settings["log_keep"].as_u64() {
+                opts.keep = keep as u32;
+            }
+            if let Some(console) = settings["log_console"].as_bool() {
+                opts.console = console;
+            }
+            if let Some(events) = settings["event_log"].as_str() {
+                opts.events = Some(PathBuf::from(crate::helper::sanitize_slashes(events)));
+            }
+        }
+
+        for o in overrides {
+            let parsed = match o.split_once('=') {
+                Some((module, level)) => parse_level(level).map(|level| opts.modules.push((module.to_string(), level))),
+                None => parse_level(o).map(|level| opts.level = level),
+            };
+            if let Err(e) = parsed {
+                problems.push(e);
+            }
+        }
+        if let Some(dir) = dir {
+            opts.dir = dir;
+        }
+        if quiet {
+            opts.console = false;
+        }
+        (opts, problems)
+    }
+
+    pub fn file(&self) -> PathBuf {
+        self.dir.join(format!("{}.log", LOG_NAME))
+    }
+
+    // next to the log unless it's been moved
+    pub fn events_file(&self) -> PathBuf {
+        self.events.clone().unwrap_or_else(|| self.dir.join("events.jsonl"))
+    }
+}
+
+// set up the global logger. @background leaves out the console since stdout is the log file there. returns the path
+// of the log file
+pub fn init(opts: &LogOptions, background: bool) -> Result<PathBuf, String> {
+    let file = opts.file();
+    let trigger: Box<dyn Trigger> = match opts.rotate {
+        Rotate::Size(limit) => Box::new(SizeTrigger::new(limit)),
+        Rotate::Every(interval) => Box::new(TimeTrigger::new(TimeTriggerConfig { interval, ..Default::default() })),
+    };
+    let pattern = opts.dir.join(format!("{}.{{}}.log", LOG_NAME));
+    let roller = FixedWindowRoller::builder()
+        .build(pattern.to_str().unwrap(), opts.keep.max(1))
+        .map_err(|e| format!("could not set up log rotation: {:?}", e))?;
+    let logfile = RollingFileAppender::builder()
+        .encoder(Box::new(PatternEncoder::new("{d(%Y-%m-%d %H:%M:%S)} {l} {t} - {m}{n}")))
+        .build(&file, Box::new(CompoundPolicy::new(trigger, Box::new(roller))))
+        .map_err(|e| format!("could not open {:?}: {:?}", file, e))?;
+
+    let mut config = Config::builder()
+        .appender(Appender::builder().build("logfile", Box::new(logfile)));
+    let mut root = Root::builder().appender("logfile");
+    if !background {
+        // quiet still shows warnings and errors so problems aren't missed
+        let console_level = if opts.console { LevelFilter::Trace } else { LevelFilter::Warn };
+        let stdout = ConsoleAppender::builder().build();
+        config = config.appender(Appender::builder()
+            .filter(Box::new(ThresholdFilter::new(console_level)))
+            .build("stdout", Box::new(stdout)));
+        root = root.appender("stdout");
+    }
+    for (module, level) in &opts.modules {
+        config = config.logger(Logger::builder().build(module, *level));
+    }
+    let config = config.build(root.build(opts.level))
+        .map_err(|e| format!("bad log config: {:?}", e))?;
+    log4rs::init_config(config).map_err(|e| format!("could not start logging: {:?}", e))?;
+    Ok(file)
+}
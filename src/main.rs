@@ -1,13 +1,9 @@
 mod helper;
 mod linux;
+mod logging;
 mod service;
 mod windows;
 use argh::FromArgs;
-use log::LevelFilter;
-use log4rs::append::console::ConsoleAppender;
-use log4rs::append::file::FileAppender;
-use log4rs::config::{Appender, Config, Root};
-use log4rs::encode::pattern::PatternEncoder;
 use std::path::PathBuf;
 
 #[derive(FromArgs)]
@@ -25,54 +21,133 @@ struct MCArgs {
     #[argh(switch, short = 'u')]
     uninstall: bool,
 
+    /// turn on launching at startup after installing
+    #[argh(switch, short = 'e')]
+    enable: bool,
+
+    /// turn off launching at startup without uninstalling
+    #[argh(switch, short = 'd')]
+    disable: bool,
+
     /// launch as background process
     #[argh(switch, short = 'b')]
     background: bool,
+
+    /// log level for everything, or module=level for a single module. can be repeated
+    #[argh(option)]
+    log_level: Vec<String>,
+
+    /// folder to write logs to
+    #[argh(option)]
+    log_dir: Option<PathBuf>,
+
+    /// only print warnings and errors to the console
+    #[argh(switch, short = 'q')]
+    quiet: bool,
+
+    #[argh(subcommand)]
+    command: Option<MCCommand>,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand)]
+enum MCCommand {
+    Tracker(service::tracker::TrackerArgs),
+    Library(service::catalogue::LibraryArgs),
+    Status(service::status::StatusArgs),
+    Scan(service::control::ScanArgs),
+    Pause(service::control::PauseArgs),
+    Resume(service::control::ResumeArgs),
+    History(service::control::HistoryArgs),
+    Restore(service::control::RestoreArgs),
+    Reload(service::control::ReloadArgs),
+    Shutdown(service::control::ShutdownArgs),
+    Conflicts(service::conflicts::ConflictsArgs),
+    Destination(service::destination::DestinationArgs),
 }
 
 fn main() {
-    helper::print_splash();
     let mut exedir = std::env::current_exe().unwrap();
     exedir.pop();
     std::env::set_current_dir(&exedir).unwrap();
 
-    // set up logging
-    let dt = chrono::Utc::now();
-    let timestamp: i64 = dt.timestamp();
-    let log = format!(r"{}/scary/log/log{}.log", exedir.to_str().unwrap(), timestamp);
-    println!("log: {}", log);
-    let logfile = FileAppender::builder()
-        .encoder(Box::new(PatternEncoder::new("{l} - {m}\n")))
-        .build(&log).unwrap();
-
-    let stdout = ConsoleAppender::builder().build();
-    let config = Config::builder()
-        .appender(Appender::builder().build("stdout", Box::new(stdout)))
-        .appender(Appender::builder().build("logfile", Box::new(logfile)))
-        .build(Root::builder()
-                   .appender("stdout")
-                   .appender("logfile")
-                   .build(LevelFilter::Info)).unwrap();
+    // parse args
+    let mcargs: MCArgs = argh::from_env();
+    if !mcargs.quiet {
+        helper::print_splash();
+    }
 
-    log4rs::init_config(config).unwrap();
+    // set up logging. settings.json is read early for the log settings, a missing or broken one is reported later
+    let settings = helper::parse_json(&mcargs.settings).ok();
+    let (log_opts, log_problems) = logging::LogOptions::load(settings.as_ref(), &mcargs.log_level,
+        mcargs.log_dir.clone(), mcargs.quiet);
+    // in the background stdout is the log file, writing to both would log everything twice
+    let log = match logging::init(&log_opts, mcargs.background) {
+        Ok(log) => log,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    if !mcargs.quiet {
+        println!("log: {}", log.display());
+    }
+    for problem in log_problems {
+        log::warn!("{}", problem);
+    }
 
     // log start time
     log::info!("{}", chrono::offset::Local::now());
-    // parse args
-    let mcargs: MCArgs = argh::from_env();
-    if mcargs.uninstall {
+    if let Some(command) = mcargs.command {
+        // only the commands working on settings.json read it, the rest just talk to the running service
+        let settings_file = &mcargs.settings;
+        let settings = || helper::parse_json(settings_file)
+            .map_err(|e| format!("could not read {:?}: {}", settings_file, e));
+        let result = match command {
+            MCCommand::Tracker(args) => settings().and_then(|settings| service::tracker::command(&settings, args)),
+            MCCommand::Library(args) => settings().and_then(|settings| service::catalogue::command(&settings, args)),
+            MCCommand::Status(args) => settings().and_then(|settings| service::status::command(&settings, args)),
+            MCCommand::Scan(args) => service::control::scan(args),
+            MCCommand::Pause(args) => service::control::pause(args),
+            MCCommand::Resume(args) => service::control::resume(args),
+            MCCommand::History(args) => service::control::history(args),
+            MCCommand::Restore(args) => service::control::restore(args),
+            MCCommand::Reload(args) => service::control::reload(args),
+            MCCommand::Shutdown(args) => service::control::shutdown(args),
+            MCCommand::Conflicts(args) => service::conflicts::command(args),
+            MCCommand::Destination(args) => {
+                settings().and_then(|settings| service::destination::command(&settings, args))
+            }
+        };
+        if let Err(e) = result {
+            log::error!("{}", e);
+            std::process::exit(1);
+        }
+    } else if mcargs.uninstall {
         log::info!("mcargs.uninstall");
         service::system::uninstall();
     } else if mcargs.install {
         log::info!("mcargs.install");
         service::system::install(true);
+    } else if mcargs.enable {
+        log::info!("mcargs.enable");
+        service::system::enable();
+    } else if mcargs.disable {
+        log::info!("mcargs.disable");
+        service::system::disable();
     } else if mcargs.background {
         log::info!("mcargs.background");
-        service::system::send_to_background();
-        std::process::exit(0);
+        if service::system::send_to_background(&log) {
+            log::info!("service::service::run() in background");
+            service::events::init(log_opts.events_file());
+            service::service::run(&mcargs.settings, true);
+        } else {
+            std::process::exit(0);
+        }
     } else {
         log::info!("service::service::run()");
-        service::service::run(&mcargs.settings);
+        service::events::init(log_opts.events_file());
+        service::service::run(&mcargs.settings, false);
     }
     log::info!("exit");
 }
@@ -0,0 +1,371 @@
+// index of every file in the library, kept in the sync folder itself so it travels with the saves. each library file
+// remembers which tracker and source file it came from, which computer copied it and every version that's been
+// copied, newest last. times are unix seconds. every computer writes only its own memurycard-catalogue/<device id>.json
+// so computers syncing into the same library never write over each other, reading merges all of them
+use crate::helper;
+use crate::service::device::Device;
+use crate::service::storage::key_for;
+use argh::FromArgs;
+use chrono::TimeZone;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+pub static CATALOGUE_DIR: &str = "memurycard-catalogue";
+// the single catalogue every computer used to share, still read but never written
+pub static CATALOGUE_FILE: &str = "memurycard-catalogue.json";
+
+// older versions are forgotten past this many so the catalogue doesn't grow forever
+const MAX_REVISIONS: usize = 50;
+
+#[derive(Serialize, Deserialize)]
+pub struct Revision {
+    pub time: i64,
+    #[serde(default)]
+    pub device: String,
+    #[serde(alias = "host")]
+    pub device_name: String,
+    pub size: u64,
+    pub sha256: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Entry {
+    pub tracker: String,
+    pub source: PathBuf,
+    #[serde(default)]
+    pub device: String,
+    #[serde(alias = "host")]
+    pub device_name: String,
+    pub first_seen: i64,
+    pub last_seen: i64,
+    pub size: u64,
+    pub sha256: String,
+    pub revisions: Vec<Revision>,
+}
+
+pub struct Catalogue {
+    // this computer's file
+    path: PathBuf,
+    root: PathBuf,
+    // keyed by the path inside the library with / separators so the catalogue reads the same on every os
+    entries: BTreeMap<String, Entry>,
+    // changed since the last flush
+    dirty: bool,
+}
+
+fn library_key(root: &Path, dst: &Path) -> Option<String> {
+    Some(key_for(dst.strip_prefix(root).ok()?))
+}
+
+// add what another computer knows about each file to @into. the newest copy's details win and the versions from both
+// are kept in time order
+fn merge(into: &mut BTreeMap<String, Entry>, from: BTreeMap<String, Entry>) {
+    for (key, mut entry) in from {
+        let existing = match into.get_mut(&key) {
+            Some(existing) => existing,
+            None => {
+                into.insert(key, entry);
+                continue;
+            }
+        };
+        if entry.last_seen > existing.last_seen {
+            std::mem::swap(existing, &mut entry);
+        }
+        existing.first_seen = existing.first_seen.min(entry.first_seen);
+        existing.revisions.append(&mut entry.revisions);
+        existing.revisions.sort_by_key(|r| r.time);
+        existing.revisions.dedup_by(|a, b| a.time == b.time && a.device == b.device && a.sha256 == b.sha256);
+        if existing.revisions.len() > MAX_REVISIONS {
+            let extra = existing.revisions.len() - MAX_REVISIONS;
+            existing.revisions.drain(..extra);
+        }
+    }
+}
+
+impl Catalogue {
+    fn read(path: &Path) -> BTreeMap<String, Entry> {
+        match std::fs::read_to_string(path) {
+            Ok(s) => serde_json::from_str(&s).unwrap_or_else(|e| {
+                log::warn!("could not parse {:?}, starting a new catalogue: {:?}", path, e);
+                BTreeMap::new()
+            }),
+            Err(_) => BTreeMap::new(),
+        }
+    }
+
+    // the catalogue this computer writes to
+    pub fn load(sync_dir: &str, device: &Device) -> Catalogue {
+        let root = PathBuf::from(sync_dir);
+        let name: String = device.id.chars()
+            .map(|c| if c.is_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+            .collect();
+        let path = root.join(CATALOGUE_DIR).join(format!("{}.json", name));
+        let entries = Catalogue::read(&path);
+        Catalogue { path, root, entries, dirty: false }
+    }
+
+    // every computer's catalogue together, for looking through
+    pub fn merged(sync_dir: &str) -> BTreeMap<String, Entry> {
+        let root = PathBuf::from(sync_dir);
+        let mut entries = Catalogue::read(&root.join(CATALOGUE_FILE));
+        let mut files: Vec<PathBuf> = std::fs::read_dir(root.join(CATALOGUE_DIR)).into_iter().flatten()
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .filter(|p| p.extension().is_some_and(|ext| ext == "json"))
+            .collect();
+        files.sort();
+        for p in files {
+            merge(&mut entries, Catalogue::read(&p));
+        }
+        entries
+    }
+
+    pub fn flush(&mut self) {
+        if !self.dirty {
+            return;
+        }
+        let written = std::fs::create_dir_all(self.path.parent().unwrap())
+            .and_then(|_| helper::write_atomic(&self.path, serde_json::to_string_pretty(&self.entries).unwrap()));
+        match written {
+            Ok(_) => self.dirty = false,
+            Err(e) => log::error!("could not save {:?}: {:?}", self.path, e),
+        }
+    }
+
+    // @src from @tracker was just copied to @dst in the library
+    pub fn record(&mut self, tracker: &str, device: &Device, src: &Path, dst: &Path, size: u64, sha256: &str) {
+        let key = match library_key(&self.root, dst) {
+            Some(key) => key,
+            None => return,
+        };
+        let now = chrono::Utc::now().timestamp();
+        self.dirty = true;
+        let entry = self.entries.entry(key).or_insert_with(|| Entry {
+            tracker: tracker.to_string(),
+            source: src.to_path_buf(),
+            device: device.id.clone(),
+            device_name: device.name.clone(),
+            first_seen: now,
+            last_seen: now,
+            size,
+            sha256: String::new(),
+            revisions: vec![],
+        });
+        entry.tracker = tracker.to_string();
+        entry.source = src.to_path_buf();
+        entry.device = device.id.clone();
+        entry.device_name = device.name.clone();
+        entry.last_seen = now;
+        entry.size = size;
+        if entry.sha256 != sha256 {
+            entry.sha256 = sha256.to_string();
+            entry.revisions.push(Revision {
+                time: now,
+                device: device.id.clone(),
+                device_name: device.name.clone(),
+                size,
+                sha256: sha256.to_string(),
+            });
+            if entry.revisions.len() > MAX_REVISIONS {
+                let extra = entry.revisions.len() - MAX_REVISIONS;
+                entry.revisions.drain(..extra);
+            }
+        }
+    }
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "library")]
+/// look through the files in the library
+pub struct LibraryArgs {
+    #[argh(subcommand)]
+    cmd: LibraryCmd,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand)]
+enum LibraryCmd {
+    List(LibraryListArgs),
+    Search(LibrarySearchArgs),
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "list")]
+/// list library files, optionally filtered
+struct LibraryListArgs {
+    /// only files from this tracker
+    #[argh(option)]
+    tracker: Option<String>,
+
+    /// only files whose library path contains this, ie the game's folder or file name
+    #[argh(option)]
+    game: Option<String>,
+
+    /// only files with this extension
+    #[argh(option)]
+    ext: Option<String>,
+
+    /// only files copied on or after this date, YYYY-MM-DD
+    #[argh(option)]
+    since: Option<String>,
+
+    /// only files copied on or before this date, YYYY-MM-DD
+    #[argh(option)]
+    until: Option<String>,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "search")]
+/// find library files whose path, source or tracker contains some text
+struct LibrarySearchArgs {
+    /// text to look for, case doesn't matter
+    #[argh(positional)]
+    query: String,
+
+    /// only files from this tracker
+    #[argh(option)]
+    tracker: Option<String>,
+
+    /// only files with this extension
+    #[argh(option)]
+    ext: Option<String>,
+
+    /// only files copied on or after this date, YYYY-MM-DD
+    #[argh(option)]
+    since: Option<String>,
+
+    /// only files copied on or before this date, YYYY-MM-DD
+    #[argh(option)]
+    until: Option<String>,
+}
+
+struct Filter {
+    tracker: Option<String>,
+    text: Option<String>,
+    search_source: bool,
+    ext: Option<String>,
+    since: Option<i64>,
+    until: Option<i64>,
+}
+
+// local midnight at the start of @date, or the end of it if @end_of_day
+fn parse_date(date: &str, end_of_day: bool) -> Result<i64, String> {
+    let day = chrono::NaiveDate::parse_from_str(date, "%Y-%m-%d")
+        .map_err(|_| format!("could not understand date \"{}\", use YYYY-MM-DD", date))?;
+    let time = if end_of_day { day.and_hms_opt(23, 59, 59) } else { day.and_hms_opt(0, 0, 0) };
+    chrono::Local.from_local_datetime(&time.unwrap()).earliest()
+        .map(|t| t.timestamp())
+        .ok_or_else(|| format!("{} doesn't exist here", date))
+}
+
+impl Filter {
+    fn matches(&self, key: &str, entry: &Entry) -> bool {
+        if let Some(tracker) = &self.tracker {
+            if entry.tracker != *tracker {
+                return false;
+            }
+        }
+        if let Some(text) = &self.text {
+            let text = text.to_lowercase();
+            let in_key = key.to_lowercase().contains(&text);
+            let in_source = self.search_source && (entry.source.to_string_lossy().to_lowercase().contains(&text)
+                || entry.tracker.to_lowercase().contains(&text));
+            if !in_key && !in_source {
+                return false;
+            }
+        }
+        if let Some(ext) = &self.ext {
+            let ext = ext.trim_start_matches('.');
+            match Path::new(key).extension() {
+                Some(e) if e.to_string_lossy().eq_ignore_ascii_case(ext) => (),
+                _ => return false,
+            }
+        }
+        if self.since.is_some_and(|since| entry.last_seen < since) {
+            return false;
+        }
+        if self.until.is_some_and(|until| entry.last_seen > until) {
+            return false;
+        }
+        true
+    }
+}
+
+fn print_entries(entries: &BTreeMap<String, Entry>, filter: &Filter) {
+    let mut found = 0;
+    for (key, entry) in entries {
+        if !filter.matches(key, entry) {
+            continue;
+        }
+        found += 1;
+        let last_seen = chrono::Local.timestamp_opt(entry.last_seen, 0).unwrap();
+        println!("{} [{}] {} bytes, {} versions, last copied {} from {}", key, entry.tracker, entry.size,
+            entry.revisions.len(), last_seen.format("%Y-%m-%d %H:%M:%S"), entry.device_name);
+        println!("  {:?}", entry.source);
+    }
+    println!("{} of {} library files", found, entries.len());
+}
+
+pub fn command(settings: &Value, args: LibraryArgs) -> Result<(), String> {
+    let entries = Catalogue::merged(&crate::service::service::sync_dir(settings));
+    let dates = |since: &Option<String>, until: &Option<String>| -> Result<(Option<i64>, Option<i64>), String> {
+        let since = since.as_deref().map(|d| parse_date(d, false)).transpose()?;
+        let until = until.as_deref().map(|d| parse_date(d, true)).transpose()?;
+        Ok((since, until))
+    };
+    let filter = match args.cmd {
+        LibraryCmd::List(a) => {
+            let (since, until) = dates(&a.since, &a.until)?;
+            Filter { tracker: a.tracker, text: a.game, search_source: false, ext: a.ext, since, until }
+        }
+        LibraryCmd::Search(a) => {
+            let (since, until) = dates(&a.since, &a.until)?;
+            Filter { tracker: a.tracker, text: Some(a.query), search_source: true, ext: a.ext, since, until }
+        }
+    };
+    print_entries(&entries, &filter);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(device: &str, time: i64, sha256: &str) -> Entry {
+        Entry {
+            tracker: "snes".to_string(),
+            source: PathBuf::from(format!("/{}/a.srm", device)),
+            device: device.to_string(),
+            device_name: device.to_string(),
+            first_seen: time,
+            last_seen: time,
+            size: 1,
+            sha256: sha256.to_string(),
+            revisions: vec![Revision {
+                time,
+                device: device.to_string(),
+                device_name: device.to_string(),
+                size: 1,
+                sha256: sha256.to_string(),
+            }],
+        }
+    }
+
+    #[test]
+    fn merge_keeps_newest_and_every_version() {
+        let mut entries = BTreeMap::new();
+        entries.insert("a.srm".to_string(), entry("laptop", 20, "bb"));
+        merge(&mut entries, BTreeMap::from([("a.srm".to_string(), entry("desktop", 10, "aa"))]));
+        merge(&mut entries, BTreeMap::from([("b.srm".to_string(), entry("desktop", 5, "cc"))]));
+
+        let a = &entries["a.srm"];
+        assert_eq!(a.device, "laptop");
+        assert_eq!(a.sha256, "bb");
+        assert_eq!(a.first_seen, 10);
+        let versions: Vec<&str> = a.revisions.iter().map(|r| r.sha256.as_str()).collect();
+        assert_eq!(versions, ["aa", "bb"]);
+        assert_eq!(entries["b.srm"].device, "desktop");
+    }
+}
@@ -0,0 +1,334 @@
+// saves that changed both here and in the library since they were last synced. the state index remembers what both
+// sides looked like after the last copy, if both have changed since and don't match anymore neither can be copied over
+// the other without losing something. what happens then is up to the tracker's "conflicts" setting:
+// "manual" (the default) keeps both versions as conflict copies next to the library save and leaves the save alone
+// until it's resolved with the conflicts command
+// "newest", "local" and "library" pick a side straight away and keep the other one as a conflict copy
+// cloud services that sync the library or a save folder make their own conflict copies when two computers change a
+// file at once. those are never copied like saves, they're listed as conflicts to resolve by hand instead
+use crate::service::control;
+use argh::FromArgs;
+use chrono::TimeZone;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+static CONFLICTS_FILE: &str = "scary/conflicts.json";
+
+// conflict copies are named <stem>.conflict-<side>-<time>.<ext> so they keep their extension
+static CONFLICT_MARK: &str = ".conflict-";
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Strategy {
+    Manual,
+    Newest,
+    Local,
+    Library,
+}
+
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Keep {
+    Local,
+    Library,
+    // the cloud service's conflict copy, only ever picked by hand
+    Cloud,
+}
+
+impl Strategy {
+    pub fn from_tracker(save: &Value) -> Strategy {
+        match save["conflicts"].as_str() {
+            Some("manual") | None => Strategy::Manual,
+            Some("newest") => Strategy::Newest,
+            Some("local") => Strategy::Local,
+            Some("library") => Strategy::Library,
+            Some(other) => {
+                log::warn!("unknown conflicts setting \"{}\", resolving by hand", other);
+                Strategy::Manual
+            }
+        }
+    }
+
+    // which side wins, None if a person has to decide
+    pub fn pick(&self, src: &Path, lib: &Path) -> Option<Keep> {
+        let modified = |p: &Path| std::fs::metadata(p).and_then(|m| m.modified()).unwrap_or(SystemTime::UNIX_EPOCH);
+        match self {
+            Strategy::Manual => None,
+            Strategy::Local => Some(Keep::Local),
+            Strategy::Library => Some(Keep::Library),
+            Strategy::Newest if modified(src) >= modified(lib) => Some(Keep::Local),
+            Strategy::Newest => Some(Keep::Library),
+        }
+    }
+}
+
+impl Keep {
+    pub fn parse(s: &str) -> Result<Keep, String> {
+        match s {
+            "local" => Ok(Keep::Local),
+            "library" => Ok(Keep::Library),
+            "cloud" => Ok(Keep::Cloud),
+            _ => Err(format!("keep should be local, library or cloud, not \"{}\"", s)),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Keep::Local => "local",
+            Keep::Library => "library",
+            Keep::Cloud => "cloud",
+        }
+    }
+}
+
+// where the @side version of the library save @lib is kept during a conflict
+pub fn copy_path(lib: &Path, side: &str) -> PathBuf {
+    let stem = lib.file_stem().unwrap_or_default().to_string_lossy();
+    let time = chrono::Local::now().format("%Y%m%d-%H%M%S");
+    let mut name = format!("{}{}{}-{}", stem, CONFLICT_MARK, side, time);
+    if let Some(ext) = lib.extension() {
+        name.push('.');
+        name.push_str(&ext.to_string_lossy());
+    }
+    lib.with_file_name(name)
+}
+
+pub fn is_conflict_copy(p: &Path) -> bool {
+    p.file_name().unwrap_or_default().to_string_lossy().contains(CONFLICT_MARK)
+}
+
+// the file a cloud service's conflict copy @p was made from, None if @p isn't one. the names look like:
+// dropbox      a (alex's conflicted copy 2024-01-01).sav
+// nextcloud    a (conflicted copy 2024-01-01 123456).sav
+// owncloud     a_conflict-20240101-123456.sav
+// syncthing    a.sync-conflict-20240101-123456-ABCDEFG.sav
+pub fn cloud_conflict_original(p: &Path) -> Option<PathBuf> {
+    let name = p.file_name()?.to_str()?;
+    // the extension is whatever follows the marker, syncthing puts its marker before the extension's dot
+    let ext = |rest: &str| rest.find('.').map_or(String::new(), |i| rest[i..].to_string());
+    let original = if let Some(i) = name.find(".sync-conflict-") {
+        format!("{}{}", &name[..i], ext(&name[i + 1..]))
+    } else if let Some((i, end)) = conflicted_copy_group(name) {
+        format!("{}{}", &name[..i], &name[end + 1..])
+    } else if let Some(i) = name.rfind("_conflict-") {
+        let rest = &name[i + "_conflict-".len()..];
+        let stamp = rest.split('.').next().unwrap_or_default();
+        if stamp.is_empty() || !stamp.chars().all(|c| c.is_ascii_digit() || c == '-') {
+            return None;
+        }
+        format!("{}{}", &name[..i], ext(rest))
+    } else {
+        return None;
+    };
+    if original.is_empty() || original.starts_with('.') && !name.starts_with('.') {
+        return None;
+    }
+    Some(p.with_file_name(original))
+}
+
+// where the " (... conflicted copy ...)" dropbox and nextcloud add starts and ends in @name. the name itself can have
+// groups in brackets too, ie region tags, so it's the last one that says conflicted copy
+fn conflicted_copy_group(name: &str) -> Option<(usize, usize)> {
+    name.rmatch_indices(" (").find_map(|(i, _)| {
+        let end = i + name[i..].find(')')?;
+        Some((i, end)).filter(|_| name[i..end].contains("conflicted copy"))
+    })
+}
+
+// move the cloud conflict copy @cloud next to the library save @lib under memury card's own conflict copy name, so
+// it's kept but not found again
+pub fn set_aside(cloud: &Path, lib: &Path) -> std::io::Result<PathBuf> {
+    let to = copy_path(lib, "cloud");
+    if let Some(parent) = to.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    // a cloud copy in a save folder can be on a different drive than the library
+    if std::fs::rename(cloud, &to).is_err() {
+        std::fs::copy(cloud, &to)?;
+        std::fs::remove_file(cloud)?;
+    }
+    Ok(to)
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Conflict {
+    pub tracker: String,
+    pub save: PathBuf,
+    pub library: PathBuf,
+    pub local_copy: Option<PathBuf>,
+    pub library_copy: Option<PathBuf>,
+    // a conflict copy made by a cloud service, in the save folder or the library
+    #[serde(default)]
+    pub cloud_copy: Option<PathBuf>,
+    pub time: i64,
+}
+
+// conflicts waiting to be resolved, kept across restarts
+pub struct Conflicts {
+    path: PathBuf,
+    list: Vec<Conflict>,
+}
+
+impl Conflicts {
+    pub fn load() -> Conflicts {
+        let path = PathBuf::from(CONFLICTS_FILE);
+        let list = match std::fs::read_to_string(&path) {
+            Ok(s) => serde_json::from_str(&s).unwrap_or_else(|e| {
+                log::warn!("could not parse {:?}: {:?}", path, e);
+                vec![]
+            }),
+            Err(_) => vec![],
+        };
+        if !list.is_empty() {
+            log::warn!("{} save conflicts need resolving, see memurycard conflicts list", list.len());
+        }
+        Conflicts { path, list }
+    }
+
+    fn save(&self) {
+        if let Some(parent) = self.path.parent() {
+            let _err = std::fs::create_dir_all(parent);
+        }
+        if let Err(e) = std::fs::write(&self.path, serde_json::to_string_pretty(&self.list).unwrap()) {
+            log::error!("could not save conflicts to {:?}: {:?}", self.path, e);
+        }
+    }
+
+    pub fn add(&mut self, conflict: Conflict) {
+        self.list.retain(|c| c.save != conflict.save);
+        self.list.push(conflict);
+        self.save();
+    }
+
+    // is @src waiting on a conflict to be resolved
+    pub fn is_held(&self, src: &Path) -> bool {
+        self.list.iter().any(|c| c.save == src)
+    }
+
+    pub fn has_cloud_copy(&self, p: &Path) -> bool {
+        self.list.iter().any(|c| c.cloud_copy.as_deref() == Some(p))
+    }
+
+    // stop tracking the conflict for @p, which can be either the save or its library copy
+    pub fn take(&mut self, p: &Path) -> Option<Conflict> {
+        let i = self.list.iter().position(|c| c.save == p || c.library == p)?;
+        let conflict = self.list.remove(i);
+        self.save();
+        Some(conflict)
+    }
+
+    pub fn lines(&self) -> Vec<String> {
+        if self.list.is_empty() {
+            return vec!["no conflicts".to_string()];
+        }
+        let mut lines = vec![];
+        for c in &self.list {
+            let time = chrono::Local.timestamp_opt(c.time, 0).unwrap().format("%Y-%m-%d %H:%M:%S");
+            lines.push(format!("{} {:?} <-> {:?} since {}", c.tracker, c.save, c.library, time));
+            if let Some(p) = &c.local_copy {
+                lines.push(format!("  local version: {:?}", p));
+            }
+            if let Some(p) = &c.library_copy {
+                lines.push(format!("  library version: {:?}", p));
+            }
+            if let Some(p) = &c.cloud_copy {
+                lines.push(format!("  cloud version: {:?}", p));
+            }
+        }
+        lines
+    }
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "conflicts")]
+/// list and resolve saves that changed both here and in the library
+pub struct ConflictsArgs {
+    #[argh(subcommand)]
+    cmd: ConflictsCmd,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand)]
+enum ConflictsCmd {
+    List(ConflictsListArgs),
+    Resolve(ConflictsResolveArgs),
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "list")]
+/// list conflicts waiting to be resolved
+struct ConflictsListArgs {
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "resolve")]
+/// keep one version of a conflicted save, the others stay as conflict copies
+struct ConflictsResolveArgs {
+    /// the save or its library copy
+    #[argh(positional)]
+    file: String,
+
+    /// which version to keep, local, library or cloud
+    #[argh(option)]
+    keep: String,
+}
+
+pub fn command(args: ConflictsArgs) -> Result<(), String> {
+    let lines = match args.cmd {
+        ConflictsCmd::List(_) if !control::is_running() => Conflicts::load().lines(),
+        ConflictsCmd::List(_) => control::call_lines("conflicts", json!({}))?,
+        ConflictsCmd::Resolve(a) => {
+            Keep::parse(&a.keep)?;
+            control::call_lines("resolve", json!({ "file": a.file, "keep": a.keep }))?
+        }
+    };
+    for line in lines {
+        println!("{}", line);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn original(name: &str) -> Option<String> {
+        cloud_conflict_original(Path::new(name)).map(|p| p.to_string_lossy().to_string())
+    }
+
+    #[test]
+    fn dropbox() {
+        assert_eq!(original("a (alex's conflicted copy 2024-01-01).sav").as_deref(), Some("a.sav"));
+        assert_eq!(original("Zelda (USA) (alex's conflicted copy 2024-01-01).sav").as_deref(), Some("Zelda (USA).sav"));
+        assert_eq!(original("Zelda (USA) (alex's conflicted copy 2024-01-01)").as_deref(), Some("Zelda (USA)"));
+    }
+
+    #[test]
+    fn nextcloud() {
+        assert_eq!(original("a (conflicted copy 2024-01-01 123456).sav").as_deref(), Some("a.sav"));
+        assert_eq!(original("Metroid (Europe) (En,Fr) (conflicted copy 2024-01-01 123456).srm").as_deref(),
+            Some("Metroid (Europe) (En,Fr).srm"));
+    }
+
+    #[test]
+    fn owncloud() {
+        assert_eq!(original("a_conflict-20240101-123456.sav").as_deref(), Some("a.sav"));
+        assert_eq!(original("Mario (Rev 1)_conflict-20240101-123456.sav").as_deref(), Some("Mario (Rev 1).sav"));
+        assert_eq!(original("a_conflict-notastamp.sav"), None);
+    }
+
+    #[test]
+    fn syncthing() {
+        assert_eq!(original("a.sync-conflict-20240101-123456-ABCDEFG.sav").as_deref(), Some("a.sav"));
+        assert_eq!(original("Pokemon (USA).sync-conflict-20240101-123456-ABCDEFG.sav").as_deref(),
+            Some("Pokemon (USA).sav"));
+    }
+
+    #[test]
+    fn not_conflict_copies() {
+        assert_eq!(original("Zelda (USA).sav"), None);
+        assert_eq!(original("Zelda (USA) (Rev 1).sav"), None);
+        assert_eq!(original("a_conflict-.sav"), None);
+    }
+}
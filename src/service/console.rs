@@ -0,0 +1,170 @@
+use crate::service::conflicts::Keep;
+use crate::service::service::{FileOpCmd, Reply};
+use crate::service::status;
+use rustyline::error::ReadlineError;
+use rustyline::DefaultEditor;
+use std::path::PathBuf;
+use std::sync::mpsc;
+#[cfg(unix)]
+use std::sync::Mutex;
+
+static HISTORY_FILE: &str = "scary/history.txt";
+
+static HELP: &str = r"commands:
+  help                show this message
+  status              show every tracker, its rules and files, when it was last copied and any errors
+  list                list every tracker and its filetype rules
+  pause [tracker]     stop copying saves for a tracker, or all trackers
+  resume [tracker]    start copying saves for a tracker again, or all trackers
+  scan [tracker]      copy every save for a tracker, or all trackers
+  history <file>      show when a save file was copied this session, or every commit of it in a git library
+  restore [--revision <commit>] <file>
+                      copy the library version of a save file back over the original, or the version from a
+                      commit in a git library
+  reload              re-read the tracker files
+  conflicts           list saves that changed both here and in the library
+  resolve <keep> <file>
+                      settle a conflict by keeping the local, library or cloud version
+  quit                exit memury card";
+
+// the terminal settings from before rustyline switched to raw mode. quit can come from the control socket or a signal
+// while a line is being read, and exiting then would leave the shell in raw mode
+#[cfg(unix)]
+static TERMIOS: Mutex<Option<libc::termios>> = Mutex::new(None);
+
+#[cfg(unix)]
+fn save_terminal() {
+    unsafe {
+        let mut termios = std::mem::zeroed();
+        if libc::isatty(libc::STDIN_FILENO) == 1 && libc::tcgetattr(libc::STDIN_FILENO, &mut termios) == 0 {
+            *TERMIOS.lock().unwrap() = Some(termios);
+        }
+    }
+}
+
+#[cfg(unix)]
+pub fn restore_terminal() {
+    if let Some(termios) = TERMIOS.lock().unwrap().as_ref() {
+        unsafe {
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, termios);
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn save_terminal() {}
+
+// no termios on windows, quit only comes in through the console or the control socket there
+#[cfg(not(unix))]
+pub fn restore_terminal() {}
+
+// send a command that replies and print everything it sends back
+fn request(file_op_tx: &mpsc::Sender<FileOpCmd>, cmd: impl FnOnce(Reply) -> FileOpCmd) {
+    let (reply_tx, reply_rx) = mpsc::channel();
+    file_op_tx.send(cmd(reply_tx)).unwrap();
+    for line in reply_rx {
+        println!("{}", line);
+    }
+}
+
+// returns false when the user asked to quit
+fn dispatch(line: &str, file_op_tx: &mpsc::Sender<FileOpCmd>) -> bool {
+    // everything after the command is a single argument since tracker names and paths can have spaces in them
+    let (cmd, arg) = match line.find(char::is_whitespace) {
+        Some(i) => (&line[..i], line[i..].trim()),
+        None => (line, ""),
+    };
+    let arg = if arg.is_empty() { None } else { Some(arg.to_string()) };
+
+    match (cmd, arg) {
+        ("help", _) | ("h", _) | ("?", _) => println!("{}", HELP),
+        ("status", None) => {
+            let (reply_tx, reply_rx) = mpsc::channel();
+            file_op_tx.send(FileOpCmd::Status(reply_tx)).unwrap();
+            if let Ok(report) = reply_rx.recv() {
+                for line in status::lines(&report) {
+                    println!("{}", line);
+                }
+            }
+        }
+        ("list", None) | ("ls", None) => request(file_op_tx, FileOpCmd::List),
+        ("pause", name) => request(file_op_tx, |r| FileOpCmd::Pause(name, r)),
+        ("resume", name) => request(file_op_tx, |r| FileOpCmd::Resume(name, r)),
+        ("scan", name) | ("s", name) => file_op_tx.send(FileOpCmd::Scan(name)).unwrap(),
+        ("history", Some(p)) => request(file_op_tx, |r| FileOpCmd::History(PathBuf::from(p), r)),
+        ("restore", Some(arg)) => {
+            // restore [--revision <commit>] <file>
+            let (revision, p) = match arg.strip_prefix("--revision") {
+                Some(rest) => {
+                    let (id, p) = rest.trim_start().split_once(char::is_whitespace).unwrap_or((rest.trim(), ""));
+                    (Some(id.to_string()), p.trim().to_string())
+                }
+                None => (None, arg),
+            };
+            if p.is_empty() {
+                println!("restore needs a file");
+            } else {
+                request(file_op_tx, |r| FileOpCmd::Restore(PathBuf::from(p), revision, r));
+            }
+        }
+        ("reload", None) => request(file_op_tx, FileOpCmd::Reload),
+        ("conflicts", None) => request(file_op_tx, FileOpCmd::Conflicts),
+        ("resolve", Some(arg)) => {
+            // resolve <local|library|cloud> <file>
+            let (keep, p) = arg.split_once(char::is_whitespace).unwrap_or((&arg, ""));
+            match Keep::parse(keep) {
+                Ok(keep) if !p.trim().is_empty() => {
+                    let p = PathBuf::from(p.trim());
+                    request(file_op_tx, |r| FileOpCmd::Resolve(p, keep, r));
+                }
+                Ok(_) => println!("resolve needs a file"),
+                Err(e) => println!("{}", e),
+            }
+        }
+        ("quit", None) | ("exit", None) | ("q", None) => return false,
+        ("", _) => (),
+        _ => println!("unknown command \"{}\", type help for a list of commands", line),
+    }
+    true
+}
+
+// cli thread
+pub fn interactive(file_op_tx: &mpsc::Sender<FileOpCmd>) {
+    save_terminal();
+    let mut rl = DefaultEditor::new().unwrap();
+    let _err = rl.load_history(HISTORY_FILE);
+    let mut quit = false;
+    loop {
+        match rl.readline("> ") {
+            Ok(line) => {
+                let line = line.trim();
+                if !line.is_empty() {
+                    let _err = rl.add_history_entry(line);
+                }
+                if !dispatch(line, file_op_tx) {
+                    quit = true;
+                    break;
+                }
+            }
+            Err(ReadlineError::Interrupted) => {
+                quit = true;
+                break;
+            }
+            Err(ReadlineError::Eof) => {
+                // no terminal attached, keep running without a console
+                log::info!("console closed");
+                break;
+            }
+            Err(e) => {
+                log::error!("console error: {:?}", e);
+                break;
+            }
+        }
+    }
+    if let Err(e) = rl.save_history(HISTORY_FILE) {
+        log::warn!("could not save console history: {:?}", e);
+    }
+    if quit {
+        file_op_tx.send(FileOpCmd::Quit()).unwrap();
+    }
+}
@@ -0,0 +1,379 @@
+// control socket for talking to a running memury card. requests and responses are single lines of json-rpc 2.0, ie:
+// -> {"jsonrpc": "2.0", "id": 1, "method": "pause", "params": {"tracker": "mgba"}}
+// <- {"jsonrpc": "2.0", "id": 1, "result": ["paused mgba"]}
+// results are lines of text, except status which returns the json report from status::report
+// unix systems use a unix domain socket, everything else listens on a loopback tcp port that's written to a file.
+// any local program can connect to that port, so the file also holds a random token that every request has to carry
+// as "token", and only the user who can read the file can send one
+use crate::helper;
+use crate::service::conflicts::Keep;
+use crate::service::service::{FileOpCmd, Reply};
+use argh::FromArgs;
+use serde_json::{json, Value};
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::thread;
+
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener as Listener, UnixStream as Stream};
+#[cfg(not(unix))]
+use std::net::{TcpListener as Listener, TcpStream as Stream};
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+
+#[cfg(unix)]
+fn socket_path() -> PathBuf {
+    helper::runtime_dir().join("control.sock")
+}
+
+#[cfg(not(unix))]
+fn socket_path() -> PathBuf {
+    helper::runtime_dir().join("control.port")
+}
+
+// returns the listener and the token requests need, None where the socket's own permissions keep others out
+#[cfg(unix)]
+fn bind() -> std::io::Result<(Listener, Option<String>)> {
+    let path = socket_path();
+    if path.exists() {
+        // a socket nobody answers on was left behind by a process that didn't exit cleanly
+        if Stream::connect(&path).is_ok() {
+            return Err(std::io::Error::new(std::io::ErrorKind::AddrInUse, "another memurycard is listening"));
+        }
+        std::fs::remove_file(&path)?;
+    }
+    Ok((Listener::bind(&path)?, None))
+}
+
+#[cfg(not(unix))]
+fn bind() -> std::io::Result<(Listener, Option<String>)> {
+    let mut random = [0u8; 16];
+    getrandom::getrandom(&mut random).map_err(|e| std::io::Error::other(e.to_string()))?;
+    let token: String = random.iter().map(|b| format!("{:02x}", b)).collect();
+    let listener = Listener::bind("127.0.0.1:0")?;
+    std::fs::write(socket_path(), format!("{}\n{}\n", listener.local_addr()?.port(), token))?;
+    Ok((listener, Some(token)))
+}
+
+// returns the stream and the token to send with requests
+#[cfg(unix)]
+fn connect() -> std::io::Result<(Stream, Option<String>)> {
+    Ok((Stream::connect(socket_path())?, None))
+}
+
+#[cfg(not(unix))]
+fn connect() -> std::io::Result<(Stream, Option<String>)> {
+    let bad = || std::io::Error::new(std::io::ErrorKind::InvalidData, "bad port file");
+    let file = std::fs::read_to_string(socket_path())?;
+    let mut lines = file.lines();
+    let port: u16 = lines.next().and_then(|p| p.trim().parse().ok()).ok_or_else(bad)?;
+    let token = lines.next().map(|t| t.trim().to_string()).ok_or_else(bad)?;
+    Ok((Stream::connect(("127.0.0.1", port))?, Some(token)))
+}
+
+// remove the socket so the next client doesn't try to talk to a process that's gone
+pub fn cleanup() {
+    let _err = std::fs::remove_file(socket_path());
+}
+
+// is another memury card answering on the control socket
+pub fn is_running() -> bool {
+    connect().is_ok()
+}
+
+// send a command that replies and collect everything it sends back
+fn request(file_op_tx: &mpsc::Sender<FileOpCmd>, cmd: impl FnOnce(Reply) -> FileOpCmd) -> Value {
+    let (reply_tx, reply_rx) = mpsc::channel();
+    file_op_tx.send(cmd(reply_tx)).unwrap();
+    Value::from(reply_rx.iter().collect::<Vec<String>>())
+}
+
+fn dispatch(method: &str, params: &Value, file_op_tx: &mpsc::Sender<FileOpCmd>) -> Result<Value, (i64, String)> {
+    let tracker = params["tracker"].as_str().map(|s| s.to_string());
+    let file = || match params["file"].as_str() {
+        Some(f) => Ok(PathBuf::from(f)),
+        None => Err((INVALID_PARAMS, format!("{} needs a file", method))),
+    };
+
+    match method {
+        "status" => {
+            let (reply_tx, reply_rx) = mpsc::channel();
+            file_op_tx.send(FileOpCmd::Status(reply_tx)).unwrap();
+            Ok(reply_rx.recv().unwrap_or(Value::Null))
+        }
+        "list" => Ok(request(file_op_tx, FileOpCmd::List)),
+        "scan" => {
+            file_op_tx.send(FileOpCmd::Scan(tracker)).unwrap();
+            Ok(json!([]))
+        }
+        "pause" => Ok(request(file_op_tx, |r| FileOpCmd::Pause(tracker, r))),
+        "resume" => Ok(request(file_op_tx, |r| FileOpCmd::Resume(tracker, r))),
+        "history" => {
+            let p = file()?;
+            Ok(request(file_op_tx, |r| FileOpCmd::History(p, r)))
+        }
+        "restore" => {
+            let p = file()?;
+            let revision = params["revision"].as_str().map(|id| id.to_string());
+            Ok(request(file_op_tx, |r| FileOpCmd::Restore(p, revision, r)))
+        }
+        "reload" => Ok(request(file_op_tx, FileOpCmd::Reload)),
+        "conflicts" => Ok(request(file_op_tx, FileOpCmd::Conflicts)),
+        "resolve" => {
+            let p = file()?;
+            let keep = Keep::parse(params["keep"].as_str().unwrap_or("")).map_err(|e| (INVALID_PARAMS, e))?;
+            Ok(request(file_op_tx, |r| FileOpCmd::Resolve(p, keep, r)))
+        }
+        // shutdown is answered before the watcher is told to quit, see handle_client
+        "shutdown" => Ok(json!(["shutting down"])),
+        _ => Err((METHOD_NOT_FOUND, format!("unknown method {}", method))),
+    }
+}
+
+fn handle_client(stream: Stream, token: Option<&str>, file_op_tx: &mpsc::Sender<FileOpCmd>) {
+    let mut writer = match stream.try_clone() {
+        Ok(w) => w,
+        Err(e) => {
+            log::warn!("control client error: {:?}", e);
+            return;
+        }
+    };
+    for line in BufReader::new(stream).lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => break,
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let (id, method, result) = match serde_json::from_str::<Value>(&line) {
+            Ok(req) if token.is_some() && req["token"].as_str() != token => {
+                log::warn!("control: refused a request without the right token");
+                (req["id"].clone(), String::new(), Err((INVALID_REQUEST, "wrong token".to_string())))
+            }
+            Ok(req) => {
+                let method = req["method"].as_str().unwrap_or("").to_string();
+                log::info!("control: {}", method);
+                let result = dispatch(&method, &req["params"], file_op_tx);
+                (req["id"].clone(), method, result)
+            }
+            Err(e) => (Value::Null, String::new(), Err((PARSE_ERROR, format!("{}", e)))),
+        };
+        let response = match result {
+            Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+            Err((code, message)) => json!({ "jsonrpc": "2.0", "id": id, "error": { "code": code, "message": message } }),
+        };
+        if writeln!(writer, "{}", response).is_err() {
+            break;
+        }
+        if method == "shutdown" {
+            file_op_tx.send(FileOpCmd::Quit()).unwrap();
+        }
+    }
+}
+
+// control socket thread
+pub fn serve(file_op_tx: mpsc::Sender<FileOpCmd>) {
+    let _err = std::fs::create_dir_all(helper::runtime_dir());
+    let (listener, token) = match bind() {
+        Ok(bound) => bound,
+        Err(e) => {
+            log::error!("could not open control socket {:?}: {:?}", socket_path(), e);
+            return;
+        }
+    };
+    log::info!("control socket: {:?}", socket_path());
+
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let (file_op_tx, token) = (file_op_tx.clone(), token.clone());
+                thread::spawn(move || handle_client(stream, token.as_deref(), &file_op_tx));
+            }
+            Err(e) => log::warn!("control socket error: {:?}", e),
+        }
+    }
+}
+
+// send a single request to the running memury card and wait for the result
+pub fn call(method: &str, params: Value) -> Result<Value, String> {
+    let (stream, token) = connect().map_err(|_| "memurycard isn't running".to_string())?;
+    let mut writer = stream.try_clone().map_err(|e| format!("{:?}", e))?;
+    let mut req = json!({ "jsonrpc": "2.0", "id": 1, "method": method, "params": params });
+    if let Some(token) = token {
+        req["token"] = Value::from(token);
+    }
+    writeln!(writer, "{}", req).map_err(|e| format!("could not send request: {:?}", e))?;
+
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line).map_err(|e| format!("could not read response: {:?}", e))?;
+    let response: Value = serde_json::from_str(&line).map_err(|e| format!("bad response {:?}: {}", line, e))?;
+    match response["error"]["message"].as_str() {
+        Some(message) => Err(message.to_string()),
+        None => Ok(response["result"].clone()),
+    }
+}
+
+// call @method on the running memury card and collect the lines it sends back
+pub fn call_lines(method: &str, params: Value) -> Result<Vec<String>, String> {
+    let result = call(method, params)?;
+    let lines = result.as_array().into_iter().flatten()
+        .map(|line| line.as_str().unwrap_or("").to_string())
+        .collect();
+    Ok(lines)
+}
+
+fn call_and_print(method: &str, params: Value) -> Result<(), String> {
+    for line in call_lines(method, params)? {
+        println!("{}", line);
+    }
+    Ok(())
+}
+
+fn tracker_params(tracker: Option<String>) -> Value {
+    match tracker {
+        Some(tracker) => json!({ "tracker": tracker }),
+        None => json!({}),
+    }
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "scan")]
+/// copy every save for a tracker, or all trackers
+pub struct ScanArgs {
+    /// name of the tracker to scan
+    #[argh(positional)]
+    tracker: Option<String>,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "pause")]
+/// stop copying saves for a tracker, or all trackers
+pub struct PauseArgs {
+    /// name of the tracker to pause
+    #[argh(positional)]
+    tracker: Option<String>,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "resume")]
+/// start copying saves for a tracker again, or all trackers
+pub struct ResumeArgs {
+    /// name of the tracker to resume
+    #[argh(positional)]
+    tracker: Option<String>,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "history")]
+/// show when a save was copied this session, or every commit of it in a git library
+pub struct HistoryArgs {
+    /// the save or its library copy
+    #[argh(positional)]
+    file: PathBuf,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "restore")]
+/// copy the library version of a save back over the original
+pub struct RestoreArgs {
+    /// the save to restore
+    #[argh(positional)]
+    file: PathBuf,
+
+    /// restore the version from this commit of a git library instead
+    #[argh(option)]
+    revision: Option<String>,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "reload")]
+/// make the running memury card re-read its tracker files
+pub struct ReloadArgs {
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "shutdown")]
+/// stop the running memury card
+pub struct ShutdownArgs {
+}
+
+pub fn scan(args: ScanArgs) -> Result<(), String> {
+    call_and_print("scan", tracker_params(args.tracker))
+}
+
+pub fn pause(args: PauseArgs) -> Result<(), String> {
+    call_and_print("pause", tracker_params(args.tracker))
+}
+
+pub fn resume(args: ResumeArgs) -> Result<(), String> {
+    call_and_print("resume", tracker_params(args.tracker))
+}
+
+pub fn history(args: HistoryArgs) -> Result<(), String> {
+    call_and_print("history", json!({ "file": args.file }))
+}
+
+pub fn restore(args: RestoreArgs) -> Result<(), String> {
+    call_and_print("restore", json!({ "file": args.file, "revision": args.revision }))
+}
+
+pub fn reload(_args: ReloadArgs) -> Result<(), String> {
+    call_and_print("reload", json!({}))
+}
+
+pub fn shutdown(_args: ShutdownArgs) -> Result<(), String> {
+    call_and_print("shutdown", json!({}))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    // send @request to a client handler that wants @token, returns the response
+    fn ask(token: Option<&str>, request: Value) -> (Value, Vec<FileOpCmd>) {
+        let (client, server) = Stream::pair().unwrap();
+        let (tx, rx) = mpsc::channel();
+        let token = token.map(|t| t.to_string());
+        let handler = thread::spawn(move || handle_client(server, token.as_deref(), &tx));
+        let mut writer = client.try_clone().unwrap();
+        writeln!(writer, "{}", request).unwrap();
+        let mut line = String::new();
+        BufReader::new(&client).read_line(&mut line).unwrap();
+        client.shutdown(std::net::Shutdown::Both).unwrap();
+        handler.join().unwrap();
+        (serde_json::from_str(&line).unwrap(), rx.try_iter().collect())
+    }
+
+    #[test]
+    fn token_required() {
+        let request = json!({ "jsonrpc": "2.0", "id": 1, "method": "shutdown" });
+        let (response, cmds) = ask(Some("secret"), request.clone());
+        assert_eq!(response["error"]["code"], INVALID_REQUEST);
+        assert!(cmds.is_empty());
+
+        let mut wrong = request.clone();
+        wrong["token"] = json!("guess");
+        let (response, cmds) = ask(Some("secret"), wrong);
+        assert_eq!(response["error"]["code"], INVALID_REQUEST);
+        assert!(cmds.is_empty());
+
+        let mut right = request;
+        right["token"] = json!("secret");
+        let (response, cmds) = ask(Some("secret"), right);
+        assert_eq!(response["result"], json!(["shutting down"]));
+        assert!(matches!(cmds.as_slice(), [FileOpCmd::Quit()]));
+    }
+
+    #[test]
+    fn no_token_needed_on_the_socket() {
+        let (response, cmds) = ask(None, json!({ "jsonrpc": "2.0", "id": 1, "method": "scan" }));
+        assert_eq!(response["result"], json!([]));
+        assert!(matches!(cmds.as_slice(), [FileOpCmd::Scan(None)]));
+    }
+}
@@ -0,0 +1,473 @@
+// other places saves are copied to besides the library, ie a nas or a usb drive:
+// "destinations": [{"name": "nas", "path": "/mnt/nas/saves"}, {"name": "usb", "type": "folder", "path": "E:/saves"}]
+// "type" picks the kind of storage, "folder" is the default. see sftp.rs, webdav.rs and s3.rs for the others
+// trackers send to every destination unless they list the ones they want with "destinations": ["nas"]. the library in
+// sync_path always gets every save, it's the one two way trackers and conflicts work from. each destination keeps its
+// own journal of copies to retry so one that can't be reached doesn't hold up the others
+use crate::helper;
+use crate::service::journal::Journal;
+use crate::service::service::FileOpCmd;
+use crate::service::s3::S3Storage;
+use crate::service::sftp::SftpStorage;
+use crate::service::webdav::WebdavStorage;
+use crate::service::storage::{LocalStorage, Storage};
+use argh::FromArgs;
+use chrono::TimeZone;
+use serde_json::{json, Value};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+// a destination that couldn't be connected to isn't tried again for this long, so every save waiting for it doesn't
+// have to wait out its own connection timeout
+const OFFLINE_WAIT: Duration = Duration::from_secs(60);
+
+pub struct Destination {
+    pub name: String,
+    pub storage: Box<dyn Storage>,
+}
+
+fn storage(d: &Value) -> Result<Box<dyn Storage>, String> {
+    match d["type"].as_str().unwrap_or("folder") {
+        "folder" => match d["path"].as_str() {
+            Some(path) if !path.is_empty() => {
+                Ok(Box::new(LocalStorage::new(PathBuf::from(helper::sanitize_slashes(path)))))
+            }
+            _ => Err("folder destinations need a path".to_string()),
+        },
+        "sftp" => Ok(Box::new(SftpStorage::from_settings(d)?)),
+        "webdav" => Ok(Box::new(WebdavStorage::from_settings(d)?)),
+        "s3" => Ok(Box::new(S3Storage::from_settings(d)?)),
+        other => Err(format!("unknown destination type \"{}\"", other)),
+    }
+}
+
+pub fn from_settings(settings: &Value) -> Vec<Destination> {
+    let mut destinations: Vec<Destination> = vec![];
+    for d in settings["destinations"].as_array().into_iter().flatten() {
+        let name = match d["name"].as_str() {
+            Some(name) if !name.is_empty() => name,
+            _ => {
+                log::warn!("destinations need a name, skipping {}", d);
+                continue;
+            }
+        };
+        if destinations.iter().any(|other| other.name == name) {
+            log::warn!("there's already a destination named {}, skipping {}", name, d);
+            continue;
+        }
+        match storage(d) {
+            Ok(storage) => destinations.push(Destination { name: name.to_string(), storage }),
+            Err(e) => log::warn!("{}, skipping destination {}", e, name),
+        }
+    }
+    destinations
+}
+
+// a copy for a destination's worker to make, @key being where @src goes in the destination
+pub struct Upload {
+    pub tracker: String,
+    pub src: PathBuf,
+    pub key: String,
+}
+
+// how an upload went, handed back to the watcher thread. the result is None if the destination already had the file
+pub struct Sent {
+    pub destination: String,
+    pub upload: Upload,
+    pub dst: String,
+    pub sha256: Option<String>,
+    // whether the destination could be reached once the copy was done or given up on, None if it wasn't tried
+    pub reachable: Option<bool>,
+    pub result: std::io::Result<Option<u64>>,
+}
+
+// a destination and the copies to it that haven't gone through yet. the copies themselves are made by a thread of the
+// destination's own, so a slow or unreachable one doesn't hold up the library or the other destinations. the journal
+// is only touched by the watcher thread, from the results the worker sends back
+pub struct Target {
+    pub name: String,
+    location: String,
+    pub journal: Journal,
+    // what the worker found the last time it sent something, None before it has
+    pub reachable: Option<bool>,
+    offline_until: Option<Instant>,
+    uploads: Option<mpsc::Sender<Upload>>,
+}
+
+fn journal_path(name: &str) -> PathBuf {
+    let name: String = name.chars()
+        .map(|c| if c.is_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+        .collect();
+    PathBuf::from(format!("scary/journal-{}.jsonl", name))
+}
+
+impl Target {
+    pub fn load(dest: &Destination) -> Target {
+        let mut journal = Journal::open(journal_path(&dest.name));
+        // copies left over from the last run only live in this journal, try them again straight away
+        journal.retry_all();
+        Target {
+            name: dest.name.clone(),
+            location: dest.storage.location(),
+            journal,
+            reachable: None,
+            offline_until: None,
+            uploads: None,
+        }
+    }
+
+
+    // hand @dest to a new worker that sends its results to @done. a worker started before finishes what it was given
+    // and stops
+    pub fn start(&mut self, dest: Destination, done: mpsc::Sender<FileOpCmd>) {
+        let (tx, rx) = mpsc::channel();
+        self.location = dest.storage.location();
+        self.uploads = Some(tx);
+        std::thread::spawn(move || work(dest, rx, done));
+    }
+
+    // queue @upload for the worker, it's given back if there's no worker to take it
+    pub fn upload(&self, upload: Upload) -> Result<(), Upload> {
+        match &self.uploads {
+            Some(uploads) => uploads.send(upload).map_err(|e| e.0),
+            None => Err(upload),
+        }
+    }
+
+    // @key written out in full, the way the destination's storage would for its worker
+    pub fn describe(&self, key: &str) -> String {
+        format!("{}/{}", self.location.trim_end_matches('/'), key)
+    }
+
+    pub fn available(&self) -> bool {
+        self.offline_until.is_none_or(|until| Instant::now() >= until)
+    }
+
+    pub fn went_offline(&mut self) {
+        log::warn!("destination {} can't be reached, trying again in {}s", self.name, OFFLINE_WAIT.as_secs());
+        self.offline_until = Some(Instant::now() + OFFLINE_WAIT);
+    }
+
+    pub fn report(&self) -> Value {
+        let errors: Vec<Value> = self.journal.jobs().iter()
+            .filter_map(|(src, job)| {
+                job.error.as_ref().map(|e| json!({ "file": src, "attempts": job.attempts, "error": e }))
+            })
+            .collect();
+        json!({
+            "name": self.name,
+            "path": self.location,
+            "reachable": self.reachable,
+            "retry_in": self.offline_until.and_then(|until| until.checked_duration_since(Instant::now()))
+                .map(|wait| wait.as_secs()),
+            "pending": self.journal.jobs().len(),
+            "errors": errors,
+        })
+    }
+}
+
+// where a destination points, from its settings alone, ie without reading passwords or connecting
+fn settings_location(d: &Value) -> String {
+    let s = |key: &str| d[key].as_str().unwrap_or("");
+    match s("type") {
+        "" | "folder" => helper::sanitize_slashes(s("path")),
+        "sftp" => format!("sftp://{}{}", s("host"), s("root")),
+        "webdav" => s("url").to_string(),
+        "s3" => format!("s3://{}/{}", s("bucket"), s("prefix").trim_matches('/')),
+        other => other.to_string(),
+    }
+}
+
+// every destination in @settings with its journal as it is on disk, for the status of a memury card that isn't
+// running. the storages aren't made and the journals aren't touched
+pub fn read_targets(settings: &Value) -> Vec<Target> {
+    let mut targets: Vec<Target> = vec![];
+    for d in settings["destinations"].as_array().into_iter().flatten() {
+        let name = match d["name"].as_str() {
+            Some(name) if !name.is_empty() && !targets.iter().any(|t| t.name == name) => name,
+            _ => continue,
+        };
+        targets.push(Target {
+            name: name.to_string(),
+            location: settings_location(d),
+            journal: Journal::read(journal_path(name)),
+            reachable: None,
+            offline_until: None,
+            uploads: None,
+        });
+    }
+    targets
+}
+
+// copy @src to @key unless the same file is already there. returns the bytes written, None if there was no need
+fn send(storage: &mut dyn Storage, key: &str, src: &Path, sha256: &Option<String>) -> std::io::Result<Option<u64>> {
+    if !storage.reachable() {
+        return Err(std::io::Error::new(std::io::ErrorKind::NotFound, "destination isn't reachable"));
+    }
+    if sha256.is_some() && storage.hashes() {
+        if let Some(stat) = storage.stat(key)? {
+            if stat.sha256 == *sha256 {
+                return Ok(None);
+            }
+        }
+    }
+    storage.put(key, src).map(Some)
+}
+
+// thread function making the copies to one destination
+fn work(mut dest: Destination, uploads: mpsc::Receiver<Upload>, done: mpsc::Sender<FileOpCmd>) {
+    while let Ok(first) = uploads.recv() {
+        // whatever queued up while the last copies went out is sent together, a save that changed again only once
+        let mut batch: Vec<Upload> = vec![];
+        for upload in std::iter::once(first).chain(uploads.try_iter()) {
+            batch.retain(|u| u.src != upload.src);
+            batch.push(upload);
+        }
+        // once the destination can't be connected to the rest of the batch fails straight away instead of every copy
+        // waiting out its own connection timeout
+        let mut offline: Option<String> = None;
+        for upload in batch {
+            let sha256 = helper::file_sha256(&upload.src).ok();
+            let result = match &offline {
+                Some(e) => Err(std::io::Error::new(std::io::ErrorKind::NotConnected, e.clone())),
+                None => send(dest.storage.as_mut(), &upload.key, &upload.src, &sha256),
+            };
+            if let Err(e) = &result {
+                if e.kind() == std::io::ErrorKind::NotConnected {
+                    offline = Some(e.to_string());
+                }
+            }
+            let reachable = Some(offline.is_none() && dest.storage.reachable());
+            let dst = dest.storage.describe(&upload.key);
+            let sent = Sent { destination: dest.name.clone(), upload, dst, sha256, reachable, result };
+            if done.send(FileOpCmd::Sent(sent)).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "destination")]
+/// look at and fetch the files kept in a destination
+pub struct DestinationArgs {
+    #[argh(subcommand)]
+    cmd: DestinationCmd,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand)]
+enum DestinationCmd {
+    List(DestinationListArgs),
+    Files(DestinationFilesArgs),
+    Revisions(DestinationRevisionsArgs),
+    Get(DestinationGetArgs),
+    Remove(DestinationRemoveArgs),
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "list")]
+/// list the destinations in settings.json
+struct DestinationListArgs {
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "files")]
+/// list the files kept in a destination
+struct DestinationFilesArgs {
+    /// name of the destination
+    #[argh(positional)]
+    name: String,
+
+    /// only files whose path starts with this, ie a tracker's sync folder
+    #[argh(option, default = "String::new()")]
+    prefix: String,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "revisions")]
+/// list the versions of a file a destination keeps
+struct DestinationRevisionsArgs {
+    /// name of the destination
+    #[argh(positional)]
+    name: String,
+
+    /// path of the file inside the destination, with / separators
+    #[argh(positional)]
+    file: String,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "get")]
+/// copy a file out of a destination
+struct DestinationGetArgs {
+    /// name of the destination
+    #[argh(positional)]
+    name: String,
+
+    /// path of the file inside the destination, with / separators
+    #[argh(positional)]
+    file: String,
+
+    /// where to put the copy
+    #[argh(positional)]
+    out: String,
+
+    /// version to get, from the revisions command. the newest if left out
+    #[argh(option)]
+    revision: Option<String>,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "remove")]
+/// delete a file from a destination
+struct DestinationRemoveArgs {
+    /// name of the destination
+    #[argh(positional)]
+    name: String,
+
+    /// path of the file inside the destination, with / separators
+    #[argh(positional)]
+    file: String,
+}
+
+fn time(t: i64) -> String {
+    chrono::Local.timestamp_opt(t, 0).unwrap().format("%Y-%m-%d %H:%M:%S").to_string()
+}
+
+pub fn command(settings: &Value, args: DestinationArgs) -> Result<(), String> {
+    let mut destinations = from_settings(settings);
+    let mut find = |name: &str| -> Result<Box<dyn Storage>, String> {
+        let i = destinations.iter().position(|d| d.name == name)
+            .ok_or_else(|| format!("no destination named {}", name))?;
+        Ok(destinations.remove(i).storage)
+    };
+    let io = |e: std::io::Error| e.to_string();
+    match args.cmd {
+        DestinationCmd::List(_) => {
+            for d in from_settings(settings) {
+                let reachable = if d.storage.reachable() { "reachable" } else { "unreachable" };
+                println!("{} {} [{}]", d.name, d.storage.location(), reachable);
+            }
+        }
+        DestinationCmd::Files(a) => {
+            let mut storage = find(&a.name)?;
+            let keys = storage.list(&a.prefix).map_err(io)?;
+            for key in &keys {
+                match storage.stat(key).map_err(io)? {
+                    Some(stat) => println!("{} {} bytes, {}", key, stat.size, time(stat.modified)),
+                    None => println!("{}", key),
+                }
+            }
+            println!("{} files", keys.len());
+        }
+        DestinationCmd::Revisions(a) => {
+            let mut storage = find(&a.name)?;
+            let revisions = storage.revisions(&a.file).map_err(io)?;
+            if revisions.is_empty() {
+                return Err(format!("{} isn't in {}", a.file, a.name));
+            }
+            for r in revisions {
+                println!("{} {} bytes, {}", r.id, r.size, time(r.time));
+            }
+        }
+        DestinationCmd::Get(a) => {
+            let mut storage = find(&a.name)?;
+            let out = Path::new(&a.out);
+            let bytes = match &a.revision {
+                Some(id) => storage.get_revision(&a.file, id, out),
+                None => storage.get(&a.file, out),
+            }.map_err(io)?;
+            println!("copied {} ({} bytes) to {:?}", storage.describe(&a.file), bytes, out);
+        }
+        DestinationCmd::Remove(a) => {
+            let mut storage = find(&a.name)?;
+            storage.delete(&a.file).map_err(io)?;
+            println!("removed {}", storage.describe(&a.file));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::service::storage::MemoryStorage;
+
+    fn temp_save(name: &str, contents: &[u8]) -> PathBuf {
+        let p = std::env::temp_dir().join(format!("memurycard-destination-{}-{}", name, std::process::id()));
+        std::fs::write(&p, contents).unwrap();
+        p
+    }
+
+    fn upload(src: &Path, key: &str) -> Upload {
+        Upload { tracker: "snes".to_string(), src: src.to_path_buf(), key: key.to_string() }
+    }
+
+    // run a worker over @uploads until it's done with them and collect what it sent back
+    fn run_work(storage: MemoryStorage, uploads: Vec<Upload>) -> Vec<Sent> {
+        let (tx, rx) = mpsc::channel();
+        let (done_tx, done_rx) = mpsc::channel();
+        for upload in uploads {
+            tx.send(upload).unwrap();
+        }
+        drop(tx);
+        work(Destination { name: "mem".to_string(), storage: Box::new(storage) }, rx, done_tx);
+        done_rx.try_iter()
+            .filter_map(|cmd| match cmd {
+                FileOpCmd::Sent(sent) => Some(sent),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn send_skips_the_same_file() {
+        let src = temp_save("same", b"save");
+        let sha256 = helper::file_sha256(&src).ok();
+        let mut storage = MemoryStorage::new();
+        assert_eq!(send(&mut storage, "a.srm", &src, &sha256).unwrap(), Some(4));
+        assert_eq!(send(&mut storage, "a.srm", &src, &sha256).unwrap(), None);
+        assert_eq!(storage.revisions("a.srm").unwrap().len(), 1);
+
+        std::fs::write(&src, b"newer").unwrap();
+        let sha256 = helper::file_sha256(&src).ok();
+        assert_eq!(send(&mut storage, "a.srm", &src, &sha256).unwrap(), Some(5));
+        assert_eq!(storage.revisions("a.srm").unwrap().len(), 2);
+        let _err = std::fs::remove_file(&src);
+    }
+
+    #[test]
+    fn work_sends_and_skips() {
+        let a = temp_save("work-a", b"a");
+        let b = temp_save("work-b", b"b");
+        let mut storage = MemoryStorage::new();
+        storage.put("a.srm", &a).unwrap();
+        let sent = run_work(storage, vec![upload(&a, "a.srm"), upload(&b, "b.srm"), upload(&a, "a.srm")]);
+        // the second upload of a in the same batch replaces the first
+        let results: Vec<(&str, Option<u64>)> = sent.iter()
+            .map(|s| (s.upload.key.as_str(), *s.result.as_ref().unwrap()))
+            .collect();
+        assert_eq!(results, [("b.srm", Some(1)), ("a.srm", None)]);
+        assert!(sent.iter().all(|s| s.reachable == Some(true) && s.dst.starts_with("memory:")));
+        let _err = std::fs::remove_file(&a);
+        let _err = std::fs::remove_file(&b);
+    }
+
+    #[test]
+    fn work_reports_offline() {
+        let a = temp_save("offline-a", b"a");
+        let b = temp_save("offline-b", b"b");
+        let mut storage = MemoryStorage::new();
+        storage.offline = true;
+        let sent = run_work(storage, vec![upload(&a, "a.srm"), upload(&b, "b.srm")]);
+        assert_eq!(sent.len(), 2);
+        for s in &sent {
+            assert_eq!(s.reachable, Some(false));
+            assert!(s.result.is_err());
+        }
+        let _err = std::fs::remove_file(&a);
+        let _err = std::fs::remove_file(&b);
+    }
+}
@@ -0,0 +1,97 @@
+// which computer this is, so copies from several computers syncing into the same library can be told apart. the id
+// never changes once it's made, the name is for people and defaults to the hostname:
+// "device_id": "4f1c..."       normally left out, one is made up on first run and kept in scary/device_id
+// "device_name": "living room"
+// "library_layout": "per_device" keeps every computer's copies in devices/<name>-<id> and the newest copy from any of
+// them in latest/. the default "shared" puts everything straight into the sync folder
+use crate::helper;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+
+static DEVICE_ID_FILE: &str = "scary/device_id";
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Layout {
+    Shared,
+    PerDevice,
+}
+
+impl Layout {
+    pub fn from_settings(settings: &Value) -> Layout {
+        match settings["library_layout"].as_str() {
+            Some("per_device") => Layout::PerDevice,
+            Some("shared") | None => Layout::Shared,
+            Some(other) => {
+                log::warn!("unknown library_layout \"{}\", using shared", other);
+                Layout::Shared
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Device {
+    pub id: String,
+    pub name: String,
+}
+
+// random enough to never clash between a handful of computers without pulling in a uuid crate
+fn new_id() -> String {
+    let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default();
+    let mut hasher = Sha256::new();
+    hasher.update(helper::hostname());
+    hasher.update(now.as_nanos().to_le_bytes());
+    hasher.update(std::process::id().to_le_bytes());
+    hasher.finalize().iter().take(16).map(|b| format!("{:02x}", b)).collect()
+}
+
+fn saved_id() -> String {
+    let path = PathBuf::from(DEVICE_ID_FILE);
+    if let Ok(id) = std::fs::read_to_string(&path) {
+        if !id.trim().is_empty() {
+            return id.trim().to_string();
+        }
+    }
+    let id = new_id();
+    if let Some(parent) = path.parent() {
+        let _err = std::fs::create_dir_all(parent);
+    }
+    match std::fs::write(&path, &id) {
+        Ok(_) => log::info!("new device id {}", id),
+        Err(e) => log::error!("could not save device id to {:?}: {:?}", path, e),
+    }
+    id
+}
+
+impl Device {
+    pub fn load(settings: &Value) -> Device {
+        let id = match settings["device_id"].as_str() {
+            Some(id) if !id.is_empty() => id.to_string(),
+            _ => saved_id(),
+        };
+        let name = match settings["device_name"].as_str() {
+            Some(name) if !name.is_empty() => name.to_string(),
+            _ => helper::hostname(),
+        };
+        Device { id, name }
+    }
+
+    // folder name for this computer's copies, the name alone could be shared by two computers
+    pub fn folder(&self) -> String {
+        let name: String = self.name.chars()
+            .map(|c| if c.is_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+            .collect();
+        // the id comes from settings.json and can be anything, slicing it by bytes could split a character
+        let id: String = self.id.chars().take(8).collect();
+        format!("{}-{}", name, id)
+    }
+}
+
+// where this computer copies saves to, and the folder holding the newest copy from any computer if there is one
+pub fn library_roots(sync_dir: &Path, layout: Layout, device: &Device) -> (PathBuf, Option<PathBuf>) {
+    match layout {
+        Layout::Shared => (sync_dir.to_path_buf(), None),
+        Layout::PerDevice => (sync_dir.join("devices").join(device.folder()), Some(sync_dir.join("latest"))),
+    }
+}
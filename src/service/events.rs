@@ -0,0 +1,52 @@
+// append only record of everything that happens to saves, one json object per line so it can be searched or read by
+// other tools. every event has a time and a name, the rest depends on the event:
+// {"time": "...", "event": "change", "src": "/saves/a.sav", "kind": "write"}
+// {"time": "...", "event": "rule", "tracker": "mgba", "src": "/saves/a.sav", "allowed": true}
+// {"time": "...", "event": "skip", "src": "/saves/a.sav", "reason": "not tracked"}
+// {"time": "...", "event": "queue", "tracker": "mgba", "src": "/saves/a.sav"}
+// {"time": "...", "event": "copy", "tracker": "mgba", "src": "/saves/a.sav", "dst": "/library/gba/a.sav", ...}
+// {"time": "...", "event": "copy", "tracker": "mgba", "destination": "nas", "src": "/saves/a.sav", ...}
+// {"time": "...", "event": "copy", "peer": "desktop (192.168.1.20:47820)", "src": "...", "dst": "...", ...}
+// {"time": "...", "event": "fail", "action": "copy", "tracker": "mgba", "src": "...", "dst": "...", "error": "..."}
+// {"time": "...", "event": "restore", "tracker": "mgba", "src": "/library/gba/a.sav", "dst": "/saves/a.sav", ...}
+// {"time": "...", "event": "conflict", "tracker": "mgba", "src": "/saves/a.sav", "dst": "/library/gba/a.sav", ...}
+// {"time": "...", "event": "conflict", "tracker": "mgba", "src": "/saves/a.sav", "cloud_copy": "...", ...}
+// {"time": "...", "event": "commit", "id": "4f2c...", "device": "..."}
+// {"time": "...", "event": "resolve", "tracker": "mgba", "src": "/saves/a.sav", "dst": "...", "keep": "local"}
+use serde_json::{Map, Value};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+static EVENT_LOG: Mutex<Option<(PathBuf, std::fs::File)>> = Mutex::new(None);
+
+// start writing events to @path. until this is called events are dropped
+pub fn init(path: PathBuf) {
+    if let Some(parent) = path.parent() {
+        let _err = std::fs::create_dir_all(parent);
+    }
+    match std::fs::OpenOptions::new().append(true).create(true).open(&path) {
+        Ok(file) => {
+            log::info!("event log: {:?}", path);
+            *EVENT_LOG.lock().unwrap() = Some((path, file));
+        }
+        Err(e) => log::error!("could not open event log {:?}: {:?}", path, e),
+    }
+}
+
+// @fields should be a json object, its fields are added after the time and event name
+pub fn record(event: &str, fields: Value) {
+    let mut entry = Map::new();
+    entry.insert("time".to_string(), Value::from(chrono::Local::now().to_rfc3339()));
+    entry.insert("event".to_string(), Value::from(event));
+    if let Value::Object(fields) = fields {
+        entry.extend(fields);
+    }
+
+    let mut event_log = EVENT_LOG.lock().unwrap();
+    if let Some((path, file)) = event_log.as_mut() {
+        if let Err(e) = writeln!(file, "{}", Value::Object(entry)) {
+            log::error!("could not write {:?}: {:?}", path, e);
+        }
+    }
+}
@@ -0,0 +1,277 @@
+// a library that's also a git repository, turned on with "git" in settings.json:
+// "git": true, or {"remote": "/mnt/nas/saves.git", "branch": "main"} to push every commit as well
+// the copies made while saves are changing are gathered up and committed together once things go quiet, each commit
+// naming the trackers, files and device. git log is then the library's history and any earlier version can be
+// restored. the git program does the work so remotes, keys and credentials behave the same as they do for git.
+// pushes only ever fast forward, every computer pushing to the same remote needs a "branch" of its own
+use crate::service::catalogue::{CATALOGUE_DIR, CATALOGUE_FILE};
+use crate::service::device::Device;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::io::{Error, ErrorKind, Result};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+// commits naming more files than this per tracker just count them
+const NAMED_FILES: usize = 3;
+// a commit that failed isn't tried again for this long
+const RETRY_WAIT: Duration = Duration::from_secs(60);
+// ssh for pushes when git hasn't been told to use something else. a remote that stops answering is given up on
+// instead of holding up every push after it
+static SSH_COMMAND: &str =
+    "ssh -o BatchMode=yes -o ConnectTimeout=15 -o ServerAliveInterval=15 -o ServerAliveCountMax=4";
+
+pub struct GitLibrary {
+    root: PathBuf,
+    remote: Option<String>,
+    branch: String,
+    program: String,
+    device: Device,
+    // library files copied since the last commit, by tracker
+    pending: BTreeMap<String, Vec<PathBuf>>,
+    // a push failed and is tried again with the next commit or retry
+    unpushed: bool,
+    retry_at: Option<Instant>,
+    // the push running in the background, with git's complaint if it fails
+    pushing: Option<JoinHandle<std::result::Result<(), String>>>,
+}
+
+// a commit that changed a library file
+pub struct Commit {
+    pub id: String,
+    pub time: i64,
+    pub subject: String,
+}
+
+impl GitLibrary {
+    pub fn from_settings(d: &Value, root: &Path, device: &Device) -> Option<GitLibrary> {
+        let git = &d["git"];
+        if !git.is_object() && git.as_bool() != Some(true) {
+            return None;
+        }
+        Some(GitLibrary {
+            root: root.to_path_buf(),
+            remote: git["remote"].as_str().map(|r| r.to_string()),
+            branch: git["branch"].as_str().unwrap_or("main").to_string(),
+            program: git["program"].as_str().unwrap_or("git").to_string(),
+            device: device.clone(),
+            pending: BTreeMap::new(),
+            unpushed: false,
+            retry_at: None,
+            pushing: None,
+        })
+    }
+
+    fn command(&self) -> Command {
+        let mut cmd = Command::new(&self.program);
+        cmd.arg("-C").arg(&self.root);
+        // commits are made by this computer rather than whoever is set up in git's own settings
+        let email = format!("{}@memurycard", self.device.id);
+        cmd.env("GIT_AUTHOR_NAME", &self.device.name).env("GIT_AUTHOR_EMAIL", &email);
+        cmd.env("GIT_COMMITTER_NAME", &self.device.name).env("GIT_COMMITTER_EMAIL", &email);
+        // never stop to ask for a password, there's nobody to answer
+        cmd.env("GIT_TERMINAL_PROMPT", "0");
+        cmd.stdin(Stdio::null()).stdout(Stdio::piped()).stderr(Stdio::piped());
+        cmd
+    }
+
+    // run git with @args and return what it printed
+    fn run<S: AsRef<OsStr>>(&self, args: &[S]) -> Result<Vec<u8>> {
+        let output = self.command().args(args).output()
+            .map_err(|e| Error::new(e.kind(), format!("could not run {}: {}", self.program, e)))?;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();
+            return Err(Error::other(format!("git {} failed: {}", args[0].as_ref().to_string_lossy(), stderr)));
+        }
+        Ok(output.stdout)
+    }
+
+    pub fn describe(&self) -> String {
+        match &self.remote {
+            Some(remote) => format!("{:?} pushing to {} {}", self.root, remote, self.branch),
+            None => format!("{:?}", self.root),
+        }
+    }
+
+    // make the library a repository if it isn't one yet
+    pub fn init(&self) -> Result<()> {
+        if self.root.join(".git").exists() {
+            return Ok(());
+        }
+        std::fs::create_dir_all(&self.root)?;
+        self.run(&["init", "--quiet"])?;
+        self.run(&["symbolic-ref", "HEAD", &format!("refs/heads/{}", self.branch)])?;
+        let ignore = self.root.join(".gitignore");
+        if !ignore.exists() {
+            let ignored = format!("{}\n{}/\n*.memurycard-tmp\n*.before-restore\n", CATALOGUE_FILE, CATALOGUE_DIR);
+            std::fs::write(&ignore, ignored)?;
+            self.run(&["add", ".gitignore"])?;
+        }
+        log::info!("made the library {:?} a git repository", self.root);
+        Ok(())
+    }
+
+    // @path is committed with the next batch
+    pub fn add(&mut self, tracker: &str, path: &Path) {
+        let files = self.pending.entry(tracker.to_string()).or_default();
+        if !files.iter().any(|f| f == path) {
+            files.push(path.to_path_buf());
+        }
+    }
+
+    // is there anything to commit that isn't waiting after a failed commit
+    pub fn due(&self) -> bool {
+        !self.pending.is_empty() && self.retry_at.is_none_or(|at| Instant::now() >= at)
+    }
+
+    fn rel<'a>(&self, path: &'a Path) -> Result<&'a Path> {
+        path.strip_prefix(&self.root)
+            .map_err(|_| Error::new(ErrorKind::InvalidInput, format!("{:?} isn't in the library", path)))
+    }
+
+    // ie "mgba: a.sav, b.sav; dolphin: 12 files from desktop" followed by every file on its own line
+    fn message(&self) -> String {
+        let mut subject = vec![];
+        let mut body = vec![];
+        for (tracker, files) in &self.pending {
+            let mut names: Vec<String> = files.iter()
+                .map(|f| f.file_name().unwrap_or_default().to_string_lossy().to_string())
+                .collect();
+            names.sort();
+            names.dedup();
+            if names.len() > NAMED_FILES {
+                subject.push(format!("{}: {} files", tracker, names.len()));
+            } else {
+                subject.push(format!("{}: {}", tracker, names.join(", ")));
+            }
+            for file in files {
+                let rel = self.rel(file).unwrap_or(file);
+                body.push(format!("{} {}", tracker, rel.to_string_lossy().replace('\\', "/")));
+            }
+        }
+        format!("{} from {}\n\n{}\ndevice: {} ({})\n", subject.join("; "), self.device.name, body.join("\n"),
+            self.device.name, self.device.id)
+    }
+
+    // commit everything copied since the last commit, then push it. returns the new commit, None if the copies didn't
+    // change anything
+    pub fn commit(&mut self) -> Result<Option<String>> {
+        if self.pending.is_empty() {
+            return Ok(None);
+        }
+        let committed = self.commit_pending();
+        self.retry_at = committed.as_ref().err().map(|_| Instant::now() + RETRY_WAIT);
+        committed
+    }
+
+    fn commit_pending(&mut self) -> Result<Option<String>> {
+        let mut args = vec!["add".to_string(), "--".to_string()];
+        for file in self.pending.values().flatten() {
+            // a file can be gone again by now, ie a save the game deleted
+            if file.is_file() {
+                args.push(self.rel(file)?.to_string_lossy().to_string());
+            }
+        }
+        if args.len() > 2 {
+            self.run(&args)?;
+        }
+        // git diff --quiet exits with 1 when something is staged
+        let staged = !self.command().args(["diff", "--cached", "--quiet"]).status()?.success();
+        let id = if staged {
+            self.run(&["commit".to_string(), "--quiet".to_string(), "-m".to_string(), self.message()])?;
+            let id = String::from_utf8_lossy(&self.run(&["rev-parse", "HEAD"])?).trim().to_string();
+            self.unpushed = true;
+            Some(id)
+        } else {
+            None
+        };
+        self.pending.clear();
+        self.push();
+        Ok(id)
+    }
+
+    // push to the remote if there's one and anything to push. the push runs on a thread of its own so a slow remote
+    // doesn't hold up copies, one that fails is tried again next time
+    pub fn push(&mut self) {
+        self.finish_push(false);
+        let remote = match &self.remote {
+            Some(remote) if self.unpushed && self.pushing.is_none() => remote.clone(),
+            _ => return,
+        };
+        let mut cmd = self.command();
+        cmd.args(["-c", "http.lowSpeedLimit=1000", "-c", "http.lowSpeedTime=60"]);
+        let ssh_set = std::env::var_os("GIT_SSH_COMMAND").is_some() || std::env::var_os("GIT_SSH").is_some()
+            || self.run(&["config", "core.sshCommand"]).is_ok();
+        if !ssh_set {
+            cmd.env("GIT_SSH_COMMAND", SSH_COMMAND);
+        }
+        cmd.args(["push", "--quiet", &remote, &format!("HEAD:refs/heads/{}", self.branch)]);
+        // a commit made while pushing sets this again
+        self.unpushed = false;
+        self.pushing = Some(std::thread::spawn(move || {
+            let output = cmd.output().map_err(|e| e.to_string())?;
+            if output.status.success() {
+                Ok(())
+            } else {
+                Err(String::from_utf8_lossy(&output.stderr).trim().to_string())
+            }
+        }));
+    }
+
+    // note how the push in the background went, once it's done or straight away waiting for it if @wait
+    pub fn finish_push(&mut self, wait: bool) {
+        match &self.pushing {
+            Some(pushing) if wait || pushing.is_finished() => (),
+            _ => return,
+        }
+        let result = self.pushing.take().unwrap().join().unwrap_or_else(|_| Err("push panicked".to_string()));
+        if let Err(e) = result {
+            self.unpushed = true;
+            let remote = self.remote.as_deref().unwrap_or("");
+            if e.contains("non-fast-forward") || e.contains("fetch first") {
+                log::warn!("could not push the library to {}, branch {} has commits from another computer. give every \
+                    computer a branch of its own: {}", remote, self.branch, e);
+            } else {
+                log::warn!("could not push the library to {}: {}", remote, e);
+            }
+        }
+    }
+
+    // commits that changed @path, newest first
+    pub fn log(&self, path: &Path) -> Result<Vec<Commit>> {
+        let rel = self.rel(path)?;
+        let output = self.run(&[OsStr::new("log"), OsStr::new("--format=%H %ct %s"), OsStr::new("--"),
+            rel.as_os_str()]);
+        // a new repository has no commits at all, which git reports as an error
+        let output = match output {
+            Ok(output) => output,
+            Err(_) if self.run(&["rev-parse", "--verify", "--quiet", "HEAD"]).is_err() => vec![],
+            Err(e) => return Err(e),
+        };
+        Ok(String::from_utf8_lossy(&output).lines()
+            .filter_map(|line| {
+                let mut parts = line.splitn(3, ' ');
+                Some(Commit {
+                    id: parts.next()?.to_string(),
+                    time: parts.next()?.parse().ok()?,
+                    subject: parts.next().unwrap_or("").to_string(),
+                })
+            })
+            .collect())
+    }
+
+    // write @path as it was in commit @id to @dst
+    pub fn show(&self, id: &str, path: &Path, dst: &Path) -> Result<u64> {
+        // only a commit id, anything else could be taken for an option or a different kind of revision
+        if !(4..=40).contains(&id.len()) || !id.bytes().all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b)) {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("{} isn't a commit id", id)));
+        }
+        let rel = self.rel(path)?.to_string_lossy().replace('\\', "/");
+        let bytes = self.run(&["show", &format!("{}:{}", id, rel)])?;
+        std::fs::write(dst, &bytes)?;
+        Ok(bytes.len() as u64)
+    }
+}
@@ -0,0 +1,88 @@
+// makes sure only one memury card watches the saves at a time. the first instance locks the pid file for as long as it
+// runs and writes its pid in it, later instances can't take the lock and hand off to the first one instead of starting
+// a watcher. the os lets go of the lock however a process exits, so a crash never leaves a stale lock behind and there's
+// nothing to clean up that a second instance could race with
+use crate::helper;
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Duration;
+
+// the locked pid file, held until release or exit
+static LOCK: Mutex<Option<File>> = Mutex::new(None);
+
+fn pid_path() -> PathBuf {
+    helper::runtime_dir().join("memurycard.pid")
+}
+
+fn read_pid(file: &mut File) -> Option<u32> {
+    let mut pid = String::new();
+    file.seek(SeekFrom::Start(0)).ok()?;
+    file.read_to_string(&mut pid).ok()?;
+    pid.trim().parse().ok()
+}
+
+// take the lock, returns the pid of the instance holding it if there already is one. None if that instance hasn't
+// written its pid yet
+pub fn acquire() -> Result<(), Option<u32>> {
+    let path = pid_path();
+    let _err = std::fs::create_dir_all(helper::runtime_dir());
+    let mut file = match OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&path) {
+        Ok(file) => file,
+        Err(e) => {
+            // nowhere to put the lock, better to run unprotected than not at all
+            log::error!("could not create lock {:?}: {:?}", path, e);
+            return Ok(());
+        }
+    };
+    match file.try_lock() {
+        Ok(()) => (),
+        Err(TryLockError::WouldBlock) => {
+            // the pid is written just after the lock is taken, give the other instance a moment to write it
+            for _ in 0..10 {
+                if let Some(pid) = read_pid(&mut file) {
+                    return Err(Some(pid));
+                }
+                std::thread::sleep(Duration::from_millis(50));
+            }
+            return Err(None);
+        }
+        Err(TryLockError::Error(e)) => {
+            log::error!("could not lock {:?}: {:?}", path, e);
+            return Ok(());
+        }
+    }
+    let written = file.set_len(0).and_then(|_| file.seek(SeekFrom::Start(0)))
+        .and_then(|_| writeln!(file, "{}", std::process::id()));
+    if let Err(e) = written {
+        log::error!("could not write {:?}: {:?}", path, e);
+    }
+    log::info!("pid file: {:?}", path);
+    *LOCK.lock().unwrap() = Some(file);
+    Ok(())
+}
+
+// give up the lock. the file stays, removing it could take it away from an instance that's just locked it
+pub fn release() {
+    if let Some(file) = LOCK.lock().unwrap().take() {
+        let _err = file.set_len(0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn second_lock_fails_until_released() {
+        let path = std::env::temp_dir().join(format!("memurycard-lock-{}", std::process::id()));
+        let open = || OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&path).unwrap();
+        let first = open();
+        first.try_lock().unwrap();
+        assert!(matches!(open().try_lock(), Err(TryLockError::WouldBlock)));
+        drop(first);
+        open().try_lock().unwrap();
+        let _err = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,173 @@
+// on disk record of copy jobs that haven't finished yet so they survive a crash or restart. each line is one json
+// object, jobs are added when a change is seen and finished when the copy succeeds or is no longer needed:
+// {"add": "/saves/a.sav"}
+// {"fail": "/saves/a.sav", "attempts": 1, "error": "..."}
+// {"done": "/saves/a.sav"}
+// the file only grows while running and is rewritten with just the unfinished jobs when it's loaded
+use crate::helper;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+pub static JOURNAL_FILE: &str = "scary/journal.jsonl";
+
+// first retry after this long, doubling every failure up to MAX_BACKOFF
+const BASE_BACKOFF: Duration = Duration::from_secs(5);
+const MAX_BACKOFF: Duration = Duration::from_secs(60 * 60);
+
+pub struct Job {
+    pub attempts: u32,
+    pub error: Option<String>,
+    next_try: Option<Instant>,
+}
+
+pub struct Journal {
+    path: PathBuf,
+    file: Option<std::fs::File>,
+    jobs: BTreeMap<PathBuf, Job>,
+}
+
+fn backoff(attempts: u32) -> Duration {
+    let backoff = BASE_BACKOFF * 2u32.saturating_pow(attempts.saturating_sub(1).min(16));
+    backoff.min(MAX_BACKOFF)
+}
+
+impl Journal {
+    pub fn load() -> Journal {
+        Journal::open(PathBuf::from(JOURNAL_FILE))
+    }
+
+    fn parse(path: &Path) -> BTreeMap<PathBuf, Job> {
+        let mut jobs: BTreeMap<PathBuf, Job> = BTreeMap::new();
+        if let Ok(s) = std::fs::read_to_string(path) {
+            for line in s.lines() {
+                // a line cut off by a crash is the only thing that can't parse, skip it
+                let entry: Value = match serde_json::from_str(line) {
+                    Ok(entry) => entry,
+                    Err(_) => continue,
+                };
+                if let Some(p) = entry["add"].as_str() {
+                    jobs.entry(PathBuf::from(p)).or_insert(Job { attempts: 0, error: None, next_try: None });
+                } else if let Some(p) = entry["fail"].as_str() {
+                    jobs.insert(PathBuf::from(p), Job {
+                        attempts: entry["attempts"].as_u64().unwrap_or(1) as u32,
+                        error: entry["error"].as_str().map(|s| s.to_string()),
+                        next_try: None,
+                    });
+                } else if let Some(p) = entry["done"].as_str() {
+                    jobs.remove(&PathBuf::from(p));
+                }
+            }
+        }
+        jobs
+    }
+
+    pub fn open(path: PathBuf) -> Journal {
+        let jobs = Journal::parse(&path);
+        let mut journal = Journal { path, file: None, jobs };
+        journal.compact();
+        if !journal.jobs.is_empty() {
+            log::info!("{} unfinished copies in {:?}", journal.jobs.len(), journal.path);
+        }
+        journal
+    }
+
+    // the journal at @path as it is on disk, for looking at without a running memury card. it's left untouched and
+    // nothing done to it is written back
+    pub fn read(path: PathBuf) -> Journal {
+        let jobs = Journal::parse(&path);
+        Journal { path, file: None, jobs }
+    }
+
+    // rewrite the journal with only the jobs that are still unfinished
+    fn compact(&mut self) {
+        let mut lines = String::new();
+        for (p, job) in &self.jobs {
+            let entry = if job.attempts == 0 {
+                json!({ "add": p })
+            } else {
+                json!({ "fail": p, "attempts": job.attempts, "error": job.error })
+            };
+            lines.push_str(&entry.to_string());
+            lines.push('\n');
+        }
+        if let Some(parent) = self.path.parent() {
+            let _err = std::fs::create_dir_all(parent);
+        }
+        // a crash halfway through a plain write would lose every unfinished job
+        if let Err(e) = helper::write_atomic(&self.path, lines) {
+            log::error!("could not write {:?}: {:?}", self.path, e);
+        }
+        self.file = std::fs::OpenOptions::new().append(true).create(true).open(&self.path).ok();
+    }
+
+    fn append(&mut self, entry: Value) {
+        if let Some(f) = &mut self.file {
+            if let Err(e) = writeln!(f, "{}", entry) {
+                log::error!("could not write {:?}: {:?}", self.path, e);
+            }
+        }
+    }
+
+    // a save changed and needs copying
+    pub fn add(&mut self, p: &Path) {
+        if self.jobs.contains_key(p) {
+            return;
+        }
+        self.jobs.insert(p.to_path_buf(), Job { attempts: 0, error: None, next_try: None });
+        self.append(json!({ "add": p }));
+    }
+
+    // the copy went through or isn't needed anymore
+    pub fn done(&mut self, p: &Path) {
+        if self.jobs.remove(p).is_some() {
+            self.append(json!({ "done": p }));
+        }
+    }
+
+    // the copy failed, try again later
+    pub fn fail(&mut self, p: &Path, error: &str) {
+        let job = self.jobs.entry(p.to_path_buf()).or_insert(Job { attempts: 0, error: None, next_try: None });
+        job.attempts += 1;
+        job.error = Some(error.to_string());
+        let wait = backoff(job.attempts);
+        job.next_try = Some(Instant::now() + wait);
+        log::warn!("copying {:?} failed {} times, trying again in {}s", p, job.attempts, wait.as_secs());
+        let entry = json!({ "fail": p, "attempts": job.attempts, "error": error });
+        self.append(entry);
+    }
+
+    // every unfinished job, for replaying after a restart
+    pub fn pending(&self) -> Vec<PathBuf> {
+        self.jobs.keys().cloned().collect()
+    }
+
+    // make every job due on the next call to due(), for jobs that aren't replayed some other way after a restart
+    pub fn retry_all(&mut self) {
+        let now = Instant::now();
+        for job in self.jobs.values_mut() {
+            job.next_try = Some(now);
+        }
+    }
+
+    // failed jobs whose backoff is over
+    pub fn due(&mut self) -> Vec<PathBuf> {
+        let now = Instant::now();
+        let mut due = vec![];
+        for (p, job) in self.jobs.iter_mut() {
+            if let Some(next_try) = job.next_try {
+                if next_try <= now {
+                    job.next_try = None;
+                    due.push(p.clone());
+                }
+            }
+        }
+        due
+    }
+
+    pub fn jobs(&self) -> &BTreeMap<PathBuf, Job> {
+        &self.jobs
+    }
+}
@@ -1,2 +1,23 @@
+pub mod catalogue;
+pub mod conflicts;
+pub mod console;
+pub mod control;
+pub mod destination;
+pub mod device;
+pub mod events;
+pub mod git;
+pub mod instance;
+pub mod journal;
+pub mod pause;
+pub mod peer;
+pub mod s3;
+#[allow(clippy::module_inception)]
 pub mod service;
+pub mod sftp;
+pub mod signals;
+pub mod state;
+pub mod status;
+pub mod storage;
 pub mod system;
+pub mod tracker;
+pub mod webdav;
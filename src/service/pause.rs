@@ -0,0 +1,163 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::path::PathBuf;
+
+static PAUSE_FILE: &str = "scary/paused.json";
+
+// what happens to saves that change while their tracker is paused
+#[derive(Clone, Copy, PartialEq)]
+pub enum PauseMode {
+    // remember the changed files and copy them when the tracker is resumed
+    Queue,
+    // forget about the changes, only saves made after resuming get copied
+    Drop,
+}
+
+impl PauseMode {
+    pub fn from_settings(settings: &Value) -> PauseMode {
+        match settings["pause_mode"].as_str() {
+            Some("drop") => PauseMode::Drop,
+            Some("queue") | None => PauseMode::Queue,
+            Some(other) => {
+                log::warn!("unknown pause_mode \"{}\", using queue", other);
+                PauseMode::Queue
+            }
+        }
+    }
+}
+
+// the part of the pause state that survives a restart
+#[derive(Default, Serialize, Deserialize)]
+struct PauseFile {
+    all: bool,
+    trackers: Vec<String>,
+}
+
+pub struct PauseState {
+    path: PathBuf,
+    mode: PauseMode,
+    all: bool,
+    trackers: HashSet<String>,
+    queued: HashMap<String, BTreeSet<PathBuf>>,
+}
+
+impl PauseState {
+    pub fn load(mode: PauseMode) -> PauseState {
+        let path = PathBuf::from(PAUSE_FILE);
+        let saved: PauseFile = match std::fs::read_to_string(&path) {
+            Ok(s) => serde_json::from_str(&s).unwrap_or_else(|e| {
+                log::warn!("could not parse {:?}: {:?}", path, e);
+                PauseFile::default()
+            }),
+            Err(_) => PauseFile::default(),
+        };
+        if saved.all {
+            log::info!("all trackers are paused");
+        }
+        for name in &saved.trackers {
+            log::info!("{} is paused", name);
+        }
+        PauseState {
+            path,
+            mode,
+            all: saved.all,
+            trackers: saved.trackers.into_iter().collect(),
+            queued: HashMap::new(),
+        }
+    }
+
+    fn save(&self) {
+        let mut trackers: Vec<String> = self.trackers.iter().cloned().collect();
+        trackers.sort();
+        let saved = PauseFile { all: self.all, trackers };
+        if let Some(parent) = self.path.parent() {
+            let _err = std::fs::create_dir_all(parent);
+        }
+        if let Err(e) = std::fs::write(&self.path, serde_json::to_string_pretty(&saved).unwrap()) {
+            log::error!("could not save pause state to {:?}: {:?}", self.path, e);
+        }
+    }
+
+    pub fn set_mode(&mut self, mode: PauseMode) {
+        self.mode = mode;
+    }
+
+    pub fn all_paused(&self) -> bool {
+        self.all
+    }
+
+    pub fn is_paused(&self, name: &str) -> bool {
+        self.all || self.trackers.contains(name)
+    }
+
+    // pause a single tracker by name, or everything
+    pub fn pause(&mut self, name: Option<String>) {
+        match name {
+            Some(name) => { self.trackers.insert(name); },
+            None => self.all = true,
+        }
+        self.save();
+    }
+
+    // resume a single tracker by name, or everything. returns the saves that were queued while paused and can now be
+    // copied. a tracker stays paused if everything is paused
+    pub fn resume(&mut self, name: Option<String>) -> Vec<PathBuf> {
+        let mut ready = vec![];
+        match name {
+            Some(name) => {
+                self.trackers.remove(&name);
+                if !self.all {
+                    if let Some(q) = self.queued.remove(&name) {
+                        ready.extend(q);
+                    }
+                }
+            }
+            None => {
+                self.all = false;
+                self.trackers.clear();
+                for (_, q) in self.queued.drain() {
+                    ready.extend(q);
+                }
+            }
+        }
+        self.save();
+        ready
+    }
+
+    // hang on to a save that changed while its tracker was paused, if the pause mode allows it. returns true if it
+    // was queued
+    pub fn hold(&mut self, name: &str, p: PathBuf) -> bool {
+        match self.mode {
+            PauseMode::Queue => {
+                log::info!("{} is paused, queueing {:?}", name, p);
+                self.queued.entry(name.to_string()).or_default().insert(p);
+                true
+            }
+            PauseMode::Drop => {
+                log::info!("{} is paused, skipping {:?}", name, p);
+                false
+            }
+        }
+    }
+
+    pub fn paused_trackers(&self) -> Vec<String> {
+        let mut trackers: Vec<String> = self.trackers.iter().cloned().collect();
+        trackers.sort();
+        trackers
+    }
+
+    pub fn queued_count(&self, name: &str) -> usize {
+        self.queued.get(name).map_or(0, |q| q.len())
+    }
+
+    // forget trackers that no longer exist after a reload
+    pub fn retain(&mut self, exists: impl Fn(&str) -> bool) {
+        let before = self.trackers.len();
+        self.trackers.retain(|name| exists(name));
+        self.queued.retain(|name, _| exists(name));
+        if self.trackers.len() != before {
+            self.save();
+        }
+    }
+}
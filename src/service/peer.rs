@@ -0,0 +1,611 @@
+// syncing the library straight between computers on the same network, for when there's no shared cloud folder:
+// "peers": {"listen": "0.0.0.0:47820", "connect": ["192.168.1.20:47820"], "key_file": "peer.key", "interval": 60}
+// computers that others should reach listen, and each one connects to the computers in "connect" every "interval"
+// seconds. the two sides trade lists of library files and each one takes whatever the other has that it's missing or
+// that's newer, then two way trackers bring those into the save folders like any change another computer makes to the
+// library. files that arrive are handed to the watcher thread, which writes them into the library like its own
+// copies. both ends prove they know the key from "key_file" or the environment variable named by "key_env" before
+// anything else is sent. nothing is encrypted, so keep this to networks you trust
+//
+// messages are single lines of json, a file's data is base64:
+// -> {"hello": "<device id>", "name": "<device name>", "nonce": "..."}
+// <- {"hello": "<device id>", "name": "<device name>", "nonce": "...",
+//     "proof": hmac(key, "server" + client nonce + server nonce)}
+// -> {"proof": hmac(key, "client" + server nonce + client nonce)}
+// -> {"op": "index"}                               <- {"files": [{"path": "gba/a.sav", "size": 8192, ...}]}
+// -> {"op": "get", "path": "gba/a.sav"}            <- {"file": {...}, "data": "..."}
+// -> {"op": "put", "file": {...}, "data": "..."}   <- {"ok": true}
+// anything that goes wrong is answered with {"error": "..."}
+use crate::helper;
+use crate::service::device::Device;
+use crate::service::events;
+use crate::service::service::{is_library_extra, FileOpCmd};
+use base64::Engine;
+use hmac::{Hmac, Mac, NewMac};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Error, ErrorKind, Read, Result, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const DEFAULT_PORT: u16 = 47820;
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(60);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const IO_TIMEOUT: Duration = Duration::from_secs(60);
+// nobody gets to send much before proving they have the key
+const HANDSHAKE_LIMIT: u64 = 4096;
+const MESSAGE_LIMIT: u64 = 512 * 1024 * 1024;
+
+pub struct PeerSettings {
+    listen: Option<String>,
+    connect: Vec<String>,
+    key: Vec<u8>,
+    interval: Duration,
+    root: PathBuf,
+    device: Device,
+}
+
+// a library file that came from another computer, for the watcher thread to put in place
+pub struct Received {
+    // the file as it arrived, next to @dst
+    pub tmp: PathBuf,
+    pub dst: PathBuf,
+    pub modified: SystemTime,
+    // the computer it came from and its path there, ie "desk (192.168.1.20:47820):gba/a.sav"
+    pub from: Device,
+    pub source: String,
+    pub reply: mpsc::Sender<Result<u64>>,
+}
+
+// a file in the library as the other side sees it, @path is relative to the sync folder with / between folders
+#[derive(Serialize, Deserialize, Clone)]
+struct Entry {
+    path: String,
+    size: u64,
+    // seconds since 1970
+    modified: i64,
+    sha256: String,
+}
+
+impl Entry {
+    // is this copy worth taking over @other, the one already here
+    fn newer_than(&self, other: Option<&Entry>) -> bool {
+        other.is_none_or(|other| self.sha256 != other.sha256 && self.modified > other.modified)
+    }
+}
+
+// the sync folder, with the hashes of files that haven't changed since they were last looked at
+struct Library {
+    root: PathBuf,
+    device: String,
+    name: String,
+    hashes: Mutex<HashMap<PathBuf, (u64, i64, String)>>,
+    // the watcher thread, which writes what other computers send
+    watcher: mpsc::Sender<FileOpCmd>,
+}
+
+fn modified_secs(meta: &std::fs::Metadata) -> i64 {
+    meta.modified().ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |d| d.as_secs() as i64)
+}
+
+impl Library {
+    fn index(&self) -> Vec<Entry> {
+        let mut hashes = self.hashes.lock().unwrap();
+        let mut entries = vec![];
+        let walk = walkdir::WalkDir::new(&self.root).into_iter().filter_entry(|e| e.file_name() != ".git");
+        for entry in walk.filter_map(|e| e.ok()) {
+            let p = entry.path();
+            let meta = match entry.metadata() {
+                Ok(meta) if meta.is_file() && !is_library_extra(p) => meta,
+                _ => continue,
+            };
+            let (size, modified) = (meta.len(), modified_secs(&meta));
+            let sha256 = match hashes.get(p) {
+                Some((s, m, sha256)) if *s == size && *m == modified => sha256.clone(),
+                _ => match helper::file_sha256(p) {
+                    Ok(sha256) => {
+                        hashes.insert(p.to_path_buf(), (size, modified, sha256.clone()));
+                        sha256
+                    }
+                    Err(_) => continue,
+                },
+            };
+            let rel = p.strip_prefix(&self.root).unwrap_or(p);
+            let path: Vec<String> = rel.components().map(|c| c.as_os_str().to_string_lossy().to_string()).collect();
+            entries.push(Entry { path: path.join("/"), size, modified, sha256 });
+        }
+        entries
+    }
+
+    // where @path from the other side goes, None for anything that would end up outside the sync folder
+    fn path_for(&self, path: &str) -> Option<PathBuf> {
+        let rel = Path::new(path);
+        let plain = rel.components().all(|c| matches!(c, Component::Normal(_)));
+        if path.is_empty() || !plain || is_library_extra(rel) || path.split('/').any(|part| part == ".git") {
+            return None;
+        }
+        Some(self.root.join(rel))
+    }
+
+    fn read(&self, path: &str) -> Result<(Entry, Vec<u8>)> {
+        let p = self.path_for(path).ok_or_else(|| Error::new(ErrorKind::InvalidInput, "not a library file"))?;
+        let data = std::fs::read(&p)?;
+        let entry = Entry {
+            path: path.to_string(),
+            size: data.len() as u64,
+            modified: modified_secs(&std::fs::metadata(&p)?),
+            sha256: helper::sha256_hex(&data),
+        };
+        Ok((entry, data))
+    }
+
+    // write a file from @from if it's still newer than the one here, keeping its modified time so it isn't sent
+    // straight back. returns false if it wasn't
+    fn write(&self, from: &Device, peer: &str, entry: &Entry, data: &[u8]) -> Result<bool> {
+        let p = self.path_for(&entry.path).ok_or_else(|| Error::new(ErrorKind::InvalidInput, "not a library file"))?;
+        if helper::sha256_hex(data) != entry.sha256 {
+            return Err(Error::new(ErrorKind::InvalidData, format!("{} didn't arrive intact", entry.path)));
+        }
+        let current = self.read(&entry.path).ok().map(|(current, _)| current);
+        if !entry.newer_than(current.as_ref()) {
+            return Ok(false);
+        }
+        if let Some(parent) = p.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let tmp = helper::temp_path(&p);
+        let (reply, result) = mpsc::channel();
+        let received = Received {
+            tmp: tmp.clone(),
+            dst: p,
+            modified: UNIX_EPOCH + Duration::from_secs(entry.modified.max(0) as u64),
+            from: from.clone(),
+            source: format!("{}:{}", peer, entry.path),
+            reply,
+        };
+        let written = std::fs::write(&tmp, data)
+            .and_then(|_| self.watcher.send(FileOpCmd::Received(received))
+                .map_err(|_| Error::new(ErrorKind::BrokenPipe, "the watcher has stopped")))
+            .and_then(|_| result.recv().unwrap_or_else(|_| Err(Error::new(ErrorKind::BrokenPipe, "shutting down"))));
+        let _err = std::fs::remove_file(&tmp);
+        written.map(|_| true)
+    }
+}
+
+fn read_key(d: &Value) -> std::result::Result<Vec<u8>, String> {
+    let key = if let Some(var) = d["key_env"].as_str() {
+        std::env::var(var).map_err(|_| format!("{} isn't set", var))?
+    } else if let Some(file) = d["key_file"].as_str() {
+        let text = std::fs::read_to_string(file).map_err(|e| format!("could not read {}: {}", file, e))?;
+        text.lines().next().unwrap_or("").to_string()
+    } else {
+        return Err("peers need a shared key from \"key_file\" or \"key_env\"".to_string());
+    };
+    if key.trim().len() < 16 {
+        return Err("the peer key should be at least 16 characters".to_string());
+    }
+    Ok(key.trim().as_bytes().to_vec())
+}
+
+// host:port, or just a port to listen on every address
+fn address(v: &Value) -> Option<String> {
+    match v {
+        Value::Number(port) => port.as_u64().filter(|p| *p <= u16::MAX as u64).map(|p| format!("0.0.0.0:{}", p)),
+        Value::String(s) if s.contains(':') => Some(s.clone()),
+        Value::String(s) => Some(format!("{}:{}", s, DEFAULT_PORT)),
+        _ => None,
+    }
+}
+
+impl PeerSettings {
+    pub fn from_settings(d: &Value, sync_dir: &Path, device: &Device) -> Option<PeerSettings> {
+        let peers = &d["peers"];
+        if peers.is_null() {
+            return None;
+        }
+        let key = match read_key(peers) {
+            Ok(key) => key,
+            Err(e) => {
+                log::error!("{}, not syncing with peers", e);
+                return None;
+            }
+        };
+        Some(PeerSettings {
+            listen: address(&peers["listen"]),
+            connect: peers["connect"].as_array().into_iter().flatten().filter_map(address).collect(),
+            key,
+            interval: peers["interval"].as_u64().map_or(DEFAULT_INTERVAL, Duration::from_secs),
+            root: sync_dir.to_path_buf(),
+            device: device.clone(),
+        })
+    }
+}
+
+fn nonce() -> String {
+    let mut bytes = [0u8; 32];
+    getrandom::getrandom(&mut bytes).expect("no random numbers from the system");
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn mac(key: &[u8], parts: &[&str]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+    for part in parts {
+        mac.update(part.as_bytes());
+    }
+    mac
+}
+
+fn proof(key: &[u8], parts: &[&str]) -> String {
+    mac(key, parts).finalize().into_bytes().iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// compared in constant time so the proof can't be guessed a byte at a time
+fn check_proof(key: &[u8], parts: &[&str], proof: &Value) -> Result<()> {
+    let hex = proof.as_str().unwrap_or("");
+    let bytes: Option<Vec<u8>> = (0..hex.len()).step_by(2)
+        .map(|i| hex.get(i..i + 2).and_then(|b| u8::from_str_radix(b, 16).ok()))
+        .collect();
+    match bytes {
+        Some(bytes) if mac(key, parts).verify(&bytes).is_ok() => Ok(()),
+        _ => Err(Error::new(ErrorKind::PermissionDenied, "the other side doesn't have the same key")),
+    }
+}
+
+// one end of a connection
+struct Conn {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+    limit: u64,
+}
+
+impl Conn {
+    fn new(stream: TcpStream) -> Result<Conn> {
+        stream.set_read_timeout(Some(IO_TIMEOUT))?;
+        stream.set_write_timeout(Some(IO_TIMEOUT))?;
+        Ok(Conn { writer: stream.try_clone()?, reader: BufReader::new(stream), limit: HANDSHAKE_LIMIT })
+    }
+
+    fn send(&mut self, msg: &Value) -> Result<()> {
+        writeln!(self.writer, "{}", msg)
+    }
+
+    fn recv(&mut self) -> Result<Value> {
+        let mut line = String::new();
+        self.reader.by_ref().take(self.limit).read_line(&mut line)?;
+        if !line.ends_with('\n') {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "the other side hung up or sent too much"));
+        }
+        let msg: Value = serde_json::from_str(&line).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        match msg["error"].as_str() {
+            Some(e) => Err(Error::other(e.to_string())),
+            None => Ok(msg),
+        }
+    }
+
+    fn request(&mut self, msg: &Value) -> Result<Value> {
+        self.send(msg)?;
+        self.recv()
+    }
+}
+
+fn encode(data: &[u8]) -> String {
+    base64::engine::general_purpose::STANDARD.encode(data)
+}
+
+fn decode(msg: &Value) -> Result<(Entry, Vec<u8>)> {
+    let entry: Entry = serde_json::from_value(msg["file"].clone()).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+    let data = base64::engine::general_purpose::STANDARD.decode(msg["data"].as_str().unwrap_or(""))
+        .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+    Ok((entry, data))
+}
+
+// the computer that said @hello from @addr, and how it's shown in the log
+fn sender(hello: &Value, addr: &str) -> (Device, String) {
+    let name = hello["name"].as_str().unwrap_or("?").to_string();
+    let peer = format!("{} ({})", name, addr);
+    (Device { id: hello["hello"].as_str().unwrap_or("?").to_string(), name }, peer)
+}
+
+// @got is true for a file that came from @peer, false for one sent to it
+fn record_copy(peer: &str, library: &Library, entry: &Entry, got: bool) {
+    let local = library.root.join(&entry.path).to_string_lossy().to_string();
+    let remote = format!("{}:{}", peer, entry.path);
+    let (src, dst) = if got { (remote, local) } else { (local, remote) };
+    log::info!("copied {} to {}", src, dst);
+    events::record("copy", json!({
+        "peer": peer, "src": src, "dst": dst, "sha256": entry.sha256, "bytes": entry.size,
+    }));
+}
+
+// answer one computer that connected to us
+fn handle(stream: TcpStream, key: &[u8], library: &Library) -> Result<()> {
+    let addr = stream.peer_addr().map_or("?".to_string(), |a| a.to_string());
+    let mut conn = Conn::new(stream)?;
+    let hello = conn.recv()?;
+    let client_nonce = hello["nonce"].as_str().unwrap_or("").to_string();
+    if client_nonce.len() < 32 {
+        return Err(Error::new(ErrorKind::InvalidData, "bad hello"));
+    }
+    let server_nonce = nonce();
+    conn.send(&json!({
+        "hello": library.device, "name": library.name, "nonce": server_nonce,
+        "proof": proof(key, &["server", &client_nonce, &server_nonce]),
+    }))?;
+    let answer = conn.recv();
+    if let Err(e) = answer.and_then(|msg| check_proof(key, &["client", &server_nonce, &client_nonce], &msg["proof"])) {
+        let _err = conn.send(&json!({ "error": "wrong key" }));
+        return Err(e);
+    }
+    let (from, peer) = sender(&hello, &addr);
+    conn.limit = MESSAGE_LIMIT;
+    conn.send(&json!({ "ok": true }))?;
+    log::debug!("peer {} connected", peer);
+    loop {
+        let msg = match conn.recv() {
+            Ok(msg) => msg,
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        let reply = match msg["op"].as_str().unwrap_or("") {
+            "index" => Ok(json!({ "files": library.index() })),
+            "get" => library.read(msg["path"].as_str().unwrap_or(""))
+                .map(|(entry, data)| json!({ "file": entry, "data": encode(&data) })),
+            "put" => decode(&msg).and_then(|(entry, data)| {
+                if library.write(&from, &peer, &entry, &data)? {
+                    record_copy(&peer, library, &entry, true);
+                }
+                Ok(json!({ "ok": true }))
+            }),
+            op => Err(Error::new(ErrorKind::InvalidInput, format!("unknown op \"{}\"", op))),
+        };
+        conn.send(&reply.unwrap_or_else(|e| json!({ "error": e.to_string() })))?;
+    }
+}
+
+fn connect(addr: &str) -> Result<TcpStream> {
+    let mut last = Error::new(ErrorKind::NotFound, format!("{} doesn't resolve to an address", addr));
+    for sock in addr.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&sock, CONNECT_TIMEOUT) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last = e,
+        }
+    }
+    Err(last)
+}
+
+// trade files with the computer at @addr, returns how many went each way
+fn sync_with(addr: &str, key: &[u8], library: &Library) -> Result<(usize, usize)> {
+    let mut conn = Conn::new(connect(addr)?)?;
+    let client_nonce = nonce();
+    let hello = conn.request(&json!({ "hello": library.device, "name": library.name, "nonce": client_nonce }))?;
+    let server_nonce = hello["nonce"].as_str().unwrap_or("").to_string();
+    check_proof(key, &["server", &client_nonce, &server_nonce], &hello["proof"])?;
+    if hello["hello"].as_str() == Some(library.device.as_str()) {
+        return Err(Error::new(ErrorKind::InvalidInput, "that's this computer"));
+    }
+    conn.request(&json!({ "proof": proof(key, &["client", &server_nonce, &client_nonce]) }))?;
+    conn.limit = MESSAGE_LIMIT;
+    let (from, peer) = sender(&hello, addr);
+
+    let theirs: Vec<Entry> = serde_json::from_value(conn.request(&json!({ "op": "index" }))?["files"].take())
+        .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+    let ours: HashMap<String, Entry> = library.index().into_iter().map(|e| (e.path.clone(), e)).collect();
+    let (mut got, mut sent) = (0, 0);
+    for entry in &theirs {
+        if entry.newer_than(ours.get(&entry.path)) {
+            let (entry, data) = decode(&conn.request(&json!({ "op": "get", "path": entry.path }))?)?;
+            if library.write(&from, &peer, &entry, &data)? {
+                record_copy(&peer, library, &entry, true);
+                got += 1;
+            }
+        }
+    }
+    let theirs: HashMap<&str, &Entry> = theirs.iter().map(|e| (e.path.as_str(), e)).collect();
+    for entry in ours.values() {
+        if entry.newer_than(theirs.get(entry.path.as_str()).copied()) {
+            let (entry, data) = library.read(&entry.path)?;
+            conn.request(&json!({ "op": "put", "file": entry, "data": encode(&data) }))?;
+            record_copy(&peer, library, &entry, false);
+            sent += 1;
+        }
+    }
+    Ok((got, sent))
+}
+
+fn listen(addr: &str) -> Option<TcpListener> {
+    match TcpListener::bind(addr) {
+        Ok(listener) => {
+            log::info!("listening for peers on {}", addr);
+            Some(listener)
+        }
+        Err(e) => {
+            log::error!("could not listen for peers on {}: {:?}", addr, e);
+            None
+        }
+    }
+}
+
+fn serve(listener: TcpListener, key: Arc<Vec<u8>>, library: Arc<Library>) {
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let (key, library) = (key.clone(), library.clone());
+                thread::spawn(move || {
+                    if let Err(e) = handle(stream, &key, &library) {
+                        log::warn!("peer connection failed: {}", e);
+                    }
+                });
+            }
+            Err(e) => log::warn!("peer connection error: {:?}", e),
+        }
+    }
+}
+
+// peer thread, listens if asked to and syncs with every peer in "connect" every interval. files that come in are sent
+// to the watcher on @file_op_tx
+pub fn run(settings: PeerSettings, file_op_tx: mpsc::Sender<FileOpCmd>) {
+    let key = Arc::new(settings.key);
+    let library = Arc::new(Library {
+        root: settings.root,
+        device: settings.device.id,
+        name: settings.device.name,
+        hashes: Mutex::new(HashMap::new()),
+        watcher: file_op_tx,
+    });
+    if settings.listen.is_none() && settings.connect.is_empty() {
+        return log::warn!("peers has nothing to listen on or connect to");
+    }
+    match (settings.listen.as_deref().and_then(listen), settings.connect.is_empty()) {
+        (Some(listener), true) => return serve(listener, key, library),
+        (Some(listener), false) => {
+            let (key, library) = (key.clone(), library.clone());
+            thread::spawn(move || serve(listener, key, library));
+        }
+        (None, true) => return,
+        (None, false) => (),
+    }
+    loop {
+        for addr in &settings.connect {
+            let started = Instant::now();
+            match sync_with(addr, &key, &library) {
+                Ok((got, sent)) => {
+                    log::debug!("synced with {} in {:?}, got {} and sent {}", addr, started.elapsed(), got, sent);
+                }
+                Err(e) => {
+                    log::warn!("could not sync with peer {}: {}", addr, e);
+                    events::record("fail", json!({ "action": "peer", "peer": addr, "error": e.to_string() }));
+                }
+            }
+        }
+        thread::sleep(settings.interval);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // an empty sync folder for the computer @device in @test
+    fn library(test: &str, device: &str) -> Library {
+        library_received(test, device, Arc::new(Mutex::new(vec![])))
+    }
+
+    // same as library, with a watcher that writes what arrives and notes down where it came from in @received
+    fn library_received(test: &str, device: &str, received: Arc<Mutex<Vec<(String, String)>>>) -> Library {
+        let root = std::env::temp_dir().join(format!("memurycard-peer-{}-{}", std::process::id(), test)).join(device);
+        let _err = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(&root).unwrap();
+        let (watcher, rx) = mpsc::channel();
+        thread::spawn(move || {
+            for cmd in rx {
+                if let FileOpCmd::Received(r) = cmd {
+                    received.lock().unwrap().push((r.from.id.clone(), r.source.clone()));
+                    let written = helper::copy_atomic(&r.tmp, &r.dst).and_then(|bytes| {
+                        std::fs::File::options().write(true).open(&r.dst)?.set_modified(r.modified)?;
+                        Ok(bytes)
+                    });
+                    r.reply.send(written).unwrap();
+                }
+            }
+        });
+        let (device, name) = (device.to_string(), device.to_string());
+        Library { root, device, name, hashes: Mutex::new(HashMap::new()), watcher }
+    }
+
+    fn clean_up(test: &str) {
+        let _err = std::fs::remove_dir_all(std::env::temp_dir().join(format!("memurycard-peer-{}-{}",
+            std::process::id(), test)));
+    }
+
+    fn write(library: &Library, path: &str, data: &str, modified: u64) {
+        let p = library.root.join(path);
+        std::fs::create_dir_all(p.parent().unwrap()).unwrap();
+        std::fs::write(&p, data).unwrap();
+        let file = std::fs::File::options().write(true).open(&p).unwrap();
+        file.set_modified(UNIX_EPOCH + Duration::from_secs(modified)).unwrap();
+    }
+
+    fn read(root: &Path, path: &str) -> Option<String> {
+        std::fs::read_to_string(root.join(path)).ok()
+    }
+
+    // a second computer listening on localhost, returns its address
+    fn listening(key: &[u8], library: Library) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let (key, library) = (Arc::new(key.to_vec()), Arc::new(library));
+        thread::spawn(move || serve(listener, key, library));
+        addr
+    }
+
+    #[test]
+    fn syncs_both_ways() {
+        let key = b"a key that's long enough";
+        let (server, client) = (library("both", "server"), library("both", "client"));
+        let server_root = server.root.clone();
+        write(&server, "gba/a.sav", "a from the server", 1_000_000);
+        write(&server, "gba/b.sav", "old b", 1_000_000);
+        write(&client, "gba/b.sav", "new b", 2_000_000);
+        write(&client, "snes/c.srm", "c from the client", 1_000_000);
+        write(&client, "snes/.c.srm.1-00000000.memurycard-tmp", "half a copy", 1_000_000);
+        let addr = listening(key, server);
+
+        assert_eq!(sync_with(&addr, key, &client).unwrap(), (1, 2));
+        assert_eq!(read(&client.root, "gba/a.sav").as_deref(), Some("a from the server"));
+        assert_eq!(read(&server_root, "gba/b.sav").as_deref(), Some("new b"));
+        assert_eq!(read(&server_root, "snes/c.srm").as_deref(), Some("c from the client"));
+        assert_eq!(read(&server_root, "snes/.c.srm.1-00000000.memurycard-tmp"), None);
+        // copies keep their modified time so nothing goes back the other way
+        assert_eq!(sync_with(&addr, key, &client).unwrap(), (0, 0));
+        clean_up("both");
+    }
+
+    #[test]
+    fn received_through_the_watcher() {
+        let key = b"a key that's long enough";
+        let received = Arc::new(Mutex::new(vec![]));
+        let (server, client) = (library("watcher", "server"), library_received("watcher", "client", received.clone()));
+        write(&server, "gba/a.sav", "a from the server", 1_000_000);
+        let addr = listening(key, server);
+
+        assert_eq!(sync_with(&addr, key, &client).unwrap(), (1, 0));
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0].0, "server");
+        assert_eq!(received[0].1, format!("server ({}):gba/a.sav", addr));
+        // nothing is left behind next to the copy
+        let names: Vec<_> = std::fs::read_dir(client.root.join("gba")).unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect();
+        assert_eq!(names, vec!["a.sav"]);
+        clean_up("watcher");
+    }
+
+    #[test]
+    fn needs_the_key() {
+        let (server, client) = (library("key", "server"), library("key", "client"));
+        write(&server, "gba/a.sav", "a from the server", 1_000_000);
+        let addr = listening(b"a key that's long enough", server);
+        assert!(sync_with(&addr, b"a different key entirely", &client).is_err());
+        assert_eq!(read(&client.root, "gba/a.sav"), None);
+        clean_up("key");
+    }
+
+    #[test]
+    fn not_with_itself() {
+        let server = library("self", "same");
+        let addr = listening(b"a key that's long enough", server);
+        let client = library("self-client", "same");
+        let err = sync_with(&addr, b"a key that's long enough", &client).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        clean_up("self");
+        clean_up("self-client");
+    }
+}
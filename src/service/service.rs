@@ -1,339 +1,1182 @@
 use crate::helper;
-use notify::{watcher, DebouncedEvent, RecursiveMode, Watcher};
-use serde_json::Value;
+use crate::helper::sanitize_slashes;
+use crate::service::catalogue::{Catalogue, CATALOGUE_DIR, CATALOGUE_FILE};
+use crate::service::conflicts::{self, Conflict, Conflicts, Keep, Strategy};
+use crate::service::console;
+use crate::service::control;
+use crate::service::destination::{self, Destination, Sent, Target, Upload};
+use crate::service::device::{library_roots, Device, Layout};
+use crate::service::events;
+use crate::service::git::GitLibrary;
+use crate::service::instance;
+use crate::service::journal::Journal;
+use crate::service::pause::{PauseMode, PauseState};
+use crate::service::peer::{self, PeerSettings, Received};
+use crate::service::signals;
+use crate::service::state::StateIndex;
+use crate::service::status;
+use crate::service::storage::{key_for, LocalStorage, Storage};
+use crate::service::tracker::{get_json_settings_descriptors, tracker_dir, SaveDef, SaveOpts};
+use chrono::TimeZone;
+use notify::{watcher, DebouncedEvent, RecommendedWatcher, RecursiveMode, Watcher};
+use serde_json::{json, Value};
 use std::collections::HashMap;
-use std::path::PathBuf;
-use std::sync::mpsc;
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc, Mutex};
 use std::thread;
-use std::time::Duration;
-use structopt::StructOpt;
+use std::time::{Duration, Instant, SystemTime};
 use walkdir::WalkDir;
 
+// replies to commands that produce output are sent back as lines of text
+pub type Reply = mpsc::Sender<String>;
 
-enum FileOpCmd {
+// how often two way trackers look for newer saves in the library
+const PULL_INTERVAL: Duration = Duration::from_secs(10);
+// a save has to be left alone this long before a newer library copy is written over it, so an emulator that's in the
+// middle of saving isn't fought with
+const QUIET_TIME: Duration = Duration::from_secs(10);
+
+pub enum FileOpCmd {
     Watch(SaveDef),
     #[allow(dead_code)]
     Unwatch(String),
     Copy(PathBuf),
-    Scan(),
+    // scan a single tracker by name, or all of them
+    Scan(Option<String>),
+    // copy only the saves that changed since they were last copied
+    CatchUp(),
+    // the status report as json, see status::report
+    Status(mpsc::Sender<Value>),
+    List(Reply),
+    // pause a single tracker by name, or all of them
+    Pause(Option<String>, Reply),
+    // resume a single tracker by name, or all of them
+    Resume(Option<String>, Reply),
+    History(PathBuf, Reply),
+    // copy the library version of a save back over it, or the version from a git commit
+    Restore(PathBuf, Option<String>, Reply),
+    Reload(Reply),
+    Conflicts(Reply),
+    // keep one side of a conflict, by save or library path
+    Resolve(PathBuf, Keep, Reply),
+    // a destination's worker made a copy or couldn't
+    Sent(Sent),
+    // another computer sent a library file over a peer connection
+    Received(Received),
+    Quit(),
 }
 
-struct SaveFile {
+struct CopyRecord {
+    time: chrono::DateTime<chrono::Local>,
+    dst: PathBuf,
 }
 
-enum RuleList {
-    Allowed(Vec<String>),
-    Disallowed(Vec<String>),
+// thread to handle events coming in on the file watcher
+fn save_scanner(
+    file_scan_rx: mpsc::Receiver<notify::DebouncedEvent>,
+    file_op_tx: &mpsc::Sender<FileOpCmd>,
+    journal: &Mutex<Journal>,
+) {
+    loop {
+        match file_scan_rx.recv() {
+            Ok(event) => match event {
+                DebouncedEvent::Write(ref p) | DebouncedEvent::Chmod(ref p) | DebouncedEvent::Create(ref p) => {
+                    log::info!("{:?}", p);
+                    let kind = match event {
+                        DebouncedEvent::Write(_) => "write",
+                        DebouncedEvent::Chmod(_) => "chmod",
+                        _ => "create",
+                    };
+                    events::record("change", json!({ "src": p, "kind": kind }));
+                    journal.lock().unwrap().add(p);
+                    file_op_tx.send(FileOpCmd::Copy(p.clone())).unwrap();
+                }
+                DebouncedEvent::NoticeWrite(_) => {
+                    // log::info!("NoticeWrite {:?}", p);
+                }
+                DebouncedEvent::Remove(p) => {
+                    log::info!("Remove {:?}", p);
+                    events::record("change", json!({ "src": p, "kind": "remove" }));
+                }
+                DebouncedEvent::NoticeRemove(p) => {
+                    log::info!("NoticeRemove {:?}", p);
+                }
+                DebouncedEvent::Rename(a, b) => {
+                    log::info!("Rename {:?} -> {:?}", a, b);
+                    events::record("change", json!({ "src": a, "dst": b, "kind": "rename" }));
+                }
+                _ => (),
+            },
+            Err(e) => log::info!("watch error: {:?}", e),
+        };
+    }
 }
 
-struct SaveDir {
-    rule_list: RuleList,
+// look for the path as registered in the save_map. both files and directories can be registered so if it's a directory
+// we need to chop off portions of the file path until we either find the path that the file was registered under or
+// get to the root (ie bad file). files under paths aren't registered, only find events when the dirs has an event
+fn find_appropriate_savedef_path(p: &Path, save_map: &HashMap<PathBuf, SaveDef>) -> Result<PathBuf, String> {
+    let mut p = p.to_path_buf();
+    while !save_map.contains_key(&p) && p.parent().is_some() {
+        p.pop();
+    }
+
+    if !save_map.contains_key(&p) {
+        return Err("Path not in save map".to_string());
+    }
+
+    Ok(p)
 }
 
-enum SaveOpts {
-    File(SaveFile),
-    Dir(SaveDir),
+// where inside a library the save file @src registered under @key gets copied to
+fn library_rel(key: &Path, save_reg: &SaveDef, src: &Path) -> PathBuf {
+    let mut dst = PathBuf::new();
+    let (folder, fname) = helper::path_diff(key.to_path_buf(), src.to_path_buf());
+
+    dst.push(&save_reg.sync_loc);
+    dst.push(folder);
+    dst.push(fname);
+    if let Some(e) = src.extension() {
+        dst.set_extension(e);
+    }
+    dst
 }
 
-struct SaveDef {
-    #[allow(dead_code)]
-    name: String,
-    path: PathBuf,
-    sync_loc: PathBuf,
-    options: SaveOpts,
+// where in the library under @root the save file @src registered under @key gets copied to
+fn library_path(root: &Path, key: &Path, save_reg: &SaveDef, src: &Path) -> PathBuf {
+    root.join(library_rel(key, save_reg, src))
+}
+
+// files in the library that aren't saves, memury card's own and conflict copies from cloud services
+pub fn is_library_extra(p: &Path) -> bool {
+    let name = p.file_name().unwrap_or_default().to_string_lossy();
+    let in_catalogue = p.parent().and_then(|d| d.file_name()).is_some_and(|d| d == CATALOGUE_DIR);
+    name == CATALOGUE_FILE || in_catalogue || name.ends_with(".memurycard-tmp") || name.ends_with(".before-restore")
+        || conflicts::is_conflict_copy(p) || conflicts::cloud_conflict_original(p).is_some()
+}
+
+fn find_save_by_name<'a>(name: &str, save_map: &'a HashMap<PathBuf, SaveDef>) -> Option<&'a SaveDef> {
+    save_map.values().find(|save| save.name == name)
 }
 
-impl SaveDef {
-    fn print(&self) {
-        log::info!("{:?}", self.path);
+fn watch_save(save: SaveDef, watcher: &mut RecommendedWatcher, save_map: &mut HashMap<PathBuf, SaveDef>) {
+    print!("watch ");
+    save.print();
+    let p = save.path.clone();
+    if !p.exists() {
+        log::warn!("{:?} doesn't exist", p);
     }
+    let _err = watcher.watch(&p, RecursiveMode::Recursive);
+    save_map.entry(p).or_insert(save);
+    // TODO: if err...
 }
 
-impl SaveDir {
-    #[allow(dead_code)]
-    fn print(&self) {
-        let rule_list = match &self.rule_list {
-            RuleList::Allowed(v) =>  {
-                log::info!("allowed_filetypes");
-                v
+fn scan_save(save: &SaveDef, file_op_tx: &mpsc::Sender<FileOpCmd>) {
+    for entry in WalkDir::new(&save.path).follow_links(true).into_iter().filter_map(|e| e.ok()) {
+        let p = PathBuf::from(entry.path());
+        if p.is_file() {
+            file_op_tx.send(FileOpCmd::Copy(p)).unwrap();
+        }
+    }
+}
+
+// the parts of settings.json the watcher uses, re-read when reloading
+struct WatcherSettings {
+    sync_dir: String,
+    json_dir: String,
+    pause_mode: PauseMode,
+    device: Device,
+    // where this computer's copies go and where the newest copy from any computer goes, see device::library_roots
+    library: PathBuf,
+    latest: Option<PathBuf>,
+    // sync_dir as a storage, every write into the library goes through it
+    storage: LocalStorage,
+    destinations: Vec<Destination>,
+    // set when the library is a git repository
+    git: Option<GitLibrary>,
+    // only read at startup, the peer thread keeps them
+    peers: Option<PeerSettings>,
+}
+
+// the library folder from settings.json
+pub fn sync_dir(settings: &Value) -> String {
+    sanitize_slashes(&helper::strip_quotes(&settings["sync_path"].to_string()))
+}
+
+impl WatcherSettings {
+    fn load(settings: &PathBuf) -> Result<WatcherSettings, String> {
+        let parse = helper::parse_json(settings).map_err(|e| format!("could not read {:?}: {}", settings, e))?;
+        let device = Device::load(&parse);
+        let (library, latest) = library_roots(Path::new(&sync_dir(&parse)), Layout::from_settings(&parse), &device);
+        Ok(WatcherSettings {
+            sync_dir: sync_dir(&parse),
+            json_dir: tracker_dir(&parse),
+            pause_mode: PauseMode::from_settings(&parse),
+            library,
+            latest,
+            storage: LocalStorage::new(PathBuf::from(sync_dir(&parse))),
+            destinations: destination::from_settings(&parse),
+            git: GitLibrary::from_settings(&parse, Path::new(&sync_dir(&parse)), &device),
+            peers: PeerSettings::from_settings(&parse, Path::new(&sync_dir(&parse)), &device),
+            device,
+        })
+    }
+}
+
+// everything the watcher thread keeps track of between commands
+struct WatcherState {
+    settings: WatcherSettings,
+    save_map: HashMap<PathBuf, SaveDef>,
+    paused: PauseState,
+    history: HashMap<PathBuf, Vec<CopyRecord>>,
+    journal: Arc<Mutex<Journal>>,
+    index: StateIndex,
+    catalogue: Catalogue,
+    conflicts: Conflicts,
+    targets: Vec<Target>,
+    // where destination workers send their results
+    file_op_tx: mpsc::Sender<FileOpCmd>,
+}
+
+impl WatcherState {
+    fn copy(&mut self, src: PathBuf) {
+        // the file can be gone by the time the event is handled, ie the temporary file of an atomic copy
+        if !src.is_file() {
+            log::debug!("{:?} is no longer a file", src);
+            events::record("skip", json!({ "src": src, "reason": "not a file" }));
+            self.journal.lock().unwrap().done(&src);
+            return;
+        }
+        let key = match find_appropriate_savedef_path(&src, &self.save_map) {
+            Ok(key) => key,
+            Err(e) => {
+                // the tracker may have been removed by a reload while the event was queued
+                log::warn!("{}: {:?}", e, src);
+                events::record("skip", json!({ "src": src, "reason": "not tracked" }));
+                self.journal.lock().unwrap().done(&src);
+                return;
             }
-            RuleList::Disallowed(v) => {
-                log::info!("disallowed_filetypes");
-                v
+        };
+        let _err = format!("could not find {:?}", key);
+        let save_reg = self.save_map.get(&key).expect(&_err);
+        if let Some(original) = conflicts::cloud_conflict_original(&src) {
+            let root = self.settings.latest.as_ref().unwrap_or(&self.settings.library);
+            let lib = library_path(root, &key, save_reg, &original);
+            let name = save_reg.name.clone();
+            let wanted = match &save_reg.options {
+                SaveOpts::Dir(d) => d.meets_rules(&original),
+                SaveOpts::File(_) => true,
+            };
+            events::record("skip", json!({ "tracker": name, "src": src, "reason": "cloud conflict copy" }));
+            self.journal.lock().unwrap().done(&src);
+            if wanted {
+                self.cloud_conflict(&name, &src, &original, &lib);
             }
+            return;
+        }
+        let has_appropriate_type = match &save_reg.options {
+            SaveOpts::Dir(e) => {
+                let allowed = e.meets_rules(&src);
+                events::record("rule", json!({ "tracker": save_reg.name, "src": src, "allowed": allowed }));
+                allowed
+            },
+            _ => true,
         };
-        for i in 0 .. rule_list.len() {
-            log::info!("{}", rule_list[i]);
+        if !has_appropriate_type {
+            self.journal.lock().unwrap().done(&src);
+            return;
+        }
+        // queued saves stay in the journal so they're still waiting if we restart while paused
+        if self.paused.is_paused(&save_reg.name) {
+            if self.paused.hold(&save_reg.name, src.clone()) {
+                events::record("queue", json!({ "tracker": save_reg.name, "src": src }));
+            } else {
+                events::record("skip", json!({ "tracker": save_reg.name, "src": src, "reason": "paused" }));
+                self.journal.lock().unwrap().done(&src);
+            }
+            return;
         }
-    }
 
-    fn print_rules(&self) {
-        match &self.rule_list {
-            RuleList::Allowed(v) =>  {
-                log::info!("Allowed:");
-                for ftype in v {
-                    log::info!("\t{}", ftype);
+        let dst = library_path(&self.settings.library, &key, save_reg, &src);
+        let latest = self.settings.latest.as_ref().map(|root| library_path(root, &key, save_reg, &src));
+        // the copy other computers write to, which is what conflicts are checked against
+        let shared = latest.clone().unwrap_or_else(|| dst.clone());
+        let name = save_reg.name.clone();
+        let two_way = save_reg.two_way;
+        if self.conflicts.is_held(&src) {
+            log::info!("{:?} has a conflict to resolve first", src);
+            self.journal.lock().unwrap().done(&src);
+            return;
+        }
+        if two_way && self.diverged(&src, &shared) {
+            match self.conflict(&name, &src, &shared) {
+                Some(Keep::Local) => (),
+                Some(Keep::Library) => {
+                    self.journal.lock().unwrap().done(&src);
+                    let _err = self.pull_one(&name, &shared, &src);
+                    return;
+                }
+                None | Some(Keep::Cloud) => {
+                    self.journal.lock().unwrap().done(&src);
+                    return;
                 }
             }
-            RuleList::Disallowed(v) => {
-                log::info!("Disallowed:");
-                for disallowed in v {
-                    log::info!("\t{}", disallowed);
+        } else if two_way && self.index.known(&shared) && self.index.changed(&shared).is_some()
+            && self.index.changed(&src).is_none() {
+            // only the library changed, the next pull brings it here instead
+            self.journal.lock().unwrap().done(&src);
+            return;
+        }
+        let result = self.put_library(&src, &dst).and_then(|bytes| match &latest {
+            Some(latest) => self.put_library(&src, latest),
+            None => Ok(bytes),
+        });
+        match result {
+            Err(e) => {
+                log::info!("\nfile copy error: {:?} {:?} {:?}", e, src, dst);
+                log::info!("{:?} exists: {:?}", src, src.exists());
+                log::info!("{:?} exists: {:?}\n", dst, dst.exists());
+                let mut journal = self.journal.lock().unwrap();
+                journal.fail(&src, &e.to_string());
+                let attempts = journal.jobs().get(&src).map_or(1, |job| job.attempts);
+                events::record("fail", json!({
+                    "action": "copy", "tracker": name, "src": src, "dst": dst,
+                    "error": e.to_string(), "attempts": attempts,
+                }));
+            }
+            Ok(bytes) => {
+                self.journal.lock().unwrap().done(&src);
+                let sha256 = self.index.record(&src);
+                // remembered so a change made by another computer can be told apart from this copy. only two way
+                // trackers look at the library again, a one way tracker's copy always replaces it
+                if two_way {
+                    self.index.record(&shared);
+                }
+                if let Some(git) = self.settings.git.as_mut() {
+                    git.add(&name, &dst);
+                    if let Some(latest) = &latest {
+                        git.add(&name, latest);
+                    }
+                }
+                let device = &self.settings.device;
+                if let Some(sha256) = &sha256 {
+                    self.catalogue.record(&name, device, &src, &dst, bytes, sha256);
+                    if let Some(latest) = &latest {
+                        self.catalogue.record(&name, device, &src, latest, bytes, sha256);
+                    }
                 }
+                events::record("copy", json!({
+                    "tracker": name, "device": device.id, "src": src, "dst": dst, "latest": latest,
+                    "sha256": sha256, "bytes": bytes,
+                }));
+                self.history.entry(src.clone()).or_default().push(CopyRecord {
+                    time: chrono::Local::now(),
+                    dst,
+                });
             }
         }
+        for i in 0..self.targets.len() {
+            self.send(i, &key, &src);
+        }
     }
 
-    fn meets_rules(&self, p: &PathBuf) -> bool {
-        match &self.rule_list {
-            RuleList::Allowed(v) =>  {
-                let ext = p.extension().unwrap().to_str().unwrap();
-                for ftype in v {
-                    if ftype == ext {
-                        return true;
-                    }
-                }
-                false
+    // queue @src registered under @key for the destination self.targets[@i] if its tracker sends there
+    fn send(&mut self, i: usize, key: &Path, src: &Path) {
+        let save_reg = &self.save_map[key];
+        let target = &mut self.targets[i];
+        if !save_reg.sends_to(&target.name) {
+            return;
+        }
+        target.journal.add(src);
+        let upload = Upload {
+            tracker: save_reg.name.clone(),
+            src: src.to_path_buf(),
+            key: key_for(&library_rel(key, save_reg, src)),
+        };
+        let upload = if target.available() {
+            match target.upload(upload) {
+                Ok(()) => return,
+                Err(upload) => upload,
             }
-            RuleList::Disallowed(v) => {
-                let pstr = p.to_str().unwrap();
-                for disallowed in v {
-                    if pstr.ends_with(disallowed) {
-                        return false;
+        } else {
+            upload
+        };
+        let destination = target.name.clone();
+        let dst = target.describe(&upload.key);
+        let error = std::io::Error::new(std::io::ErrorKind::NotFound, "destination isn't reachable");
+        self.sent(Sent { destination, upload, dst, sha256: None, reachable: None, result: Err(error) });
+    }
+
+    // note down how a copy to a destination went
+    fn sent(&mut self, sent: Sent) {
+        // the destination may have been removed by a reload while the copy was made
+        let target = match self.targets.iter_mut().find(|t| t.name == sent.destination) {
+            Some(target) => target,
+            None => return,
+        };
+        let Sent { upload, dst, sha256, reachable, result, .. } = sent;
+        let src = &upload.src;
+        if reachable.is_some() {
+            target.reachable = reachable;
+        }
+        if result.as_ref().is_err_and(|e| e.kind() == std::io::ErrorKind::NotConnected) {
+            target.went_offline();
+        }
+        match result {
+            Ok(None) => target.journal.done(src),
+            Ok(Some(bytes)) => {
+                target.journal.done(src);
+                events::record("copy", json!({
+                    "tracker": upload.tracker, "destination": target.name, "src": src, "dst": dst,
+                    "sha256": sha256, "bytes": bytes,
+                }));
+            }
+            Err(e) => {
+                log::warn!("could not copy {:?} to destination {}: {:?}", src, target.name, e);
+                target.journal.fail(src, &e.to_string());
+                let attempts = target.journal.jobs().get(src).map_or(1, |job| job.attempts);
+                events::record("fail", json!({
+                    "action": "copy", "tracker": upload.tracker, "destination": target.name, "src": src,
+                    "dst": dst, "error": e.to_string(), "attempts": attempts,
+                }));
+            }
+        }
+    }
+
+    // put a file another computer sent in the library like any other copy. the state index isn't told, so two way
+    // trackers pull it into their saves like a change another computer made to a shared folder
+    fn received(&mut self, received: &Received) -> std::io::Result<u64> {
+        let Received { tmp, dst, modified, from, source, .. } = received;
+        let bytes = self.put_library(tmp, dst)?;
+        // the same modified time as the other side so it isn't sent straight back
+        std::fs::File::options().write(true).open(dst)?.set_modified(*modified)?;
+        let tracker = self.library_pairs(false).into_iter()
+            .find(|(_, lib, _)| lib == dst)
+            .map_or_else(|| "peers".to_string(), |(name, _, _)| name);
+        if let Some(git) = self.settings.git.as_mut() {
+            git.add(&tracker, dst);
+        }
+        if let Ok(sha256) = helper::file_sha256(dst) {
+            self.catalogue.record(&tracker, from, Path::new(source), dst, bytes, &sha256);
+        }
+        Ok(bytes)
+    }
+
+    // try failed destination copies again once their backoff is over
+    fn retry_destinations(&mut self) {
+        for i in 0..self.targets.len() {
+            for src in self.targets[i].journal.due() {
+                let key = match find_appropriate_savedef_path(&src, &self.save_map) {
+                    Ok(key) if src.is_file() => key,
+                    _ => {
+                        self.targets[i].journal.done(&src);
+                        continue;
                     }
-                }
-                true
+                };
+                log::info!("retrying {:?} to destination {}", src, self.targets[i].name);
+                self.send(i, &key, &src);
             }
         }
     }
-}
 
-// cli thread
-fn interactive(json_dir: &str, file_op_tx: &mpsc::Sender<FileOpCmd>) {
-    find_json_settings(json_dir, file_op_tx);
-    file_op_tx.send(FileOpCmd::Scan()).unwrap();
-    loop {
-        log::info!("Enter command: ");
-        let mut input = String::new();
-        std::io::stdin().read_line(&mut input).unwrap();
-        let input = input.trim();
-        match input {
-            "s" => {
-                file_op_tx.send(FileOpCmd::Scan()).unwrap();
+    // keep the journals of destinations that are still there after a reload
+    fn set_destinations(&mut self, destinations: Vec<Destination>) {
+        let mut old = std::mem::take(&mut self.targets);
+        for dest in destinations {
+            let mut target = match old.iter().position(|t| t.name == dest.name) {
+                Some(i) => old.remove(i),
+                None => Target::load(&dest),
+            };
+            target.start(dest, self.file_op_tx.clone());
+            self.targets.push(target);
+        }
+    }
+
+    // where the library path @p is kept in the library storage
+    fn library_key(&self, p: &Path) -> std::io::Result<String> {
+        p.strip_prefix(&self.settings.sync_dir)
+            .map(key_for)
+            .map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("{:?} isn't in the library", p)))
+    }
+
+    // copy @src to the library path @lib
+    fn put_library(&mut self, src: &Path, lib: &Path) -> std::io::Result<u64> {
+        let key = self.library_key(lib)?;
+        self.settings.storage.put(&key, src)
+    }
+
+    // copy the library path @lib to @dst
+    fn get_library(&mut self, lib: &Path, dst: &Path) -> std::io::Result<u64> {
+        let key = self.library_key(lib)?;
+        self.settings.storage.get(&key, dst)
+    }
+
+    // is the library path @lib the same file as @src
+    fn same_as_library(&mut self, src: &Path, lib: &Path) -> bool {
+        let stat = match self.library_key(lib).and_then(|key| self.settings.storage.stat(&key)) {
+            Ok(Some(stat)) => stat,
+            _ => return false,
+        };
+        // files of different sizes can't be the same, no need to hash the save
+        if std::fs::metadata(src).map_or(true, |meta| meta.len() != stat.size) {
+            return false;
+        }
+        helper::file_sha256(src).is_ok_and(|sha256| stat.sha256 == Some(sha256))
+    }
+
+    fn init_git(&self) {
+        if let Some(git) = &self.settings.git {
+            log::info!("library is a git repository: {}", git.describe());
+            if let Err(e) = git.init() {
+                log::error!("could not make the library a git repository: {}", e);
             }
-            _ => (),
         }
     }
-}
 
-// thread to handle events coming in on the file watcher
-fn save_scanner(
-    file_scan_rx: mpsc::Receiver<notify::DebouncedEvent>,
-    file_op_tx: &mpsc::Sender<FileOpCmd>,
-) {
-    loop {
-        match file_scan_rx.recv() {
-            Ok(event) => match event {
-                DebouncedEvent::Write(p) | DebouncedEvent::Chmod(p) | DebouncedEvent::Create(p) => {
-                    let p = PathBuf::from(p);
-                    log::info!("{:?}", p);
-                    file_op_tx.send(FileOpCmd::Copy(p)).unwrap();
+    // the library copy of @p, which can be a save or already a library path
+    fn library_copy(&self, p: &Path) -> Option<PathBuf> {
+        if p.starts_with(&self.settings.sync_dir) {
+            return Some(p.to_path_buf());
+        }
+        let key = find_appropriate_savedef_path(p, &self.save_map).ok()?;
+        let root = self.settings.latest.as_ref().unwrap_or(&self.settings.library);
+        Some(library_path(root, &key, &self.save_map[&key], p))
+    }
+
+    // commit the copies made since the last commit when the library is a git repository
+    fn commit_library(&mut self) {
+        let git = match self.settings.git.as_mut() {
+            Some(git) if git.due() => git,
+            _ => return,
+        };
+        match git.commit() {
+            Ok(Some(id)) => {
+                log::info!("committed the library as {}", id);
+                events::record("commit", json!({ "id": id, "device": self.settings.device.id }));
+            }
+            Ok(None) => (),
+            Err(e) => {
+                log::warn!("could not commit the library: {}", e);
+                events::record("fail", json!({ "action": "commit", "error": e.to_string() }));
+            }
+        }
+    }
+
+    // library copies paired with the local save they belong to, for two way trackers only if @two_way_only. cloud
+    // conflict copies are paired with the save they were made from
+    fn library_pairs(&self, two_way_only: bool) -> Vec<(String, PathBuf, PathBuf)> {
+        let root = self.settings.latest.as_ref().unwrap_or(&self.settings.library);
+        let mut pairs = vec![];
+        for (key, save) in &self.save_map {
+            if two_way_only && !save.two_way {
+                continue;
+            }
+            let (dir, rules) = match &save.options {
+                SaveOpts::File(_) => {
+                    let lib = library_path(root, key, save, key);
+                    let siblings = lib.parent().and_then(|dir| std::fs::read_dir(dir).ok());
+                    for entry in siblings.into_iter().flatten().filter_map(|e| e.ok()) {
+                        if conflicts::cloud_conflict_original(&entry.path()).as_ref() == Some(&lib) {
+                            pairs.push((save.name.clone(), entry.path(), key.clone()));
+                        }
+                    }
+                    pairs.push((save.name.clone(), lib, key.clone()));
+                    continue;
                 }
-                DebouncedEvent::NoticeWrite(_) => {
-                    // log::info!("NoticeWrite {:?}", p);
+                SaveOpts::Dir(d) => (root.join(&save.sync_loc), d),
+            };
+            // a tracker syncing into the root of the library shouldn't pick up other trackers' folders
+            let others: Vec<PathBuf> = self.save_map.values()
+                .filter(|other| other.name != save.name && !other.sync_loc.as_os_str().is_empty())
+                .map(|other| root.join(&other.sync_loc))
+                .collect();
+            let entries = WalkDir::new(&dir).into_iter().filter_entry(|e| e.file_name() != ".git");
+            for entry in entries.filter_map(|e| e.ok()) {
+                let lib = entry.path();
+                let original = conflicts::cloud_conflict_original(lib);
+                let extra = is_library_extra(lib) && original.is_none();
+                if !lib.is_file() || extra || others.iter().any(|o| lib.starts_with(o)) {
+                    continue;
                 }
-                DebouncedEvent::Remove(p) => {
-                    log::info!("Remove {:?}", p);
+                let src = key.join(original.as_deref().unwrap_or(lib).strip_prefix(&dir).unwrap());
+                if rules.meets_rules(&src) {
+                    pairs.push((save.name.clone(), lib.to_path_buf(), src));
                 }
-                DebouncedEvent::NoticeRemove(p) => {
-                    log::info!("NoticeRemove {:?}", p);
+            }
+        }
+        pairs
+    }
+
+    // is the emulator likely still writing @src
+    fn busy(&self, src: &Path) -> bool {
+        let modified = std::fs::metadata(src).and_then(|m| m.modified()).unwrap_or(SystemTime::UNIX_EPOCH);
+        let recent = modified.elapsed().map_or(true, |age| age < QUIET_TIME);
+        recent || self.journal.lock().unwrap().jobs().contains_key(src)
+    }
+
+    // copy library saves that another computer changed back over the local saves of two way trackers. the state index
+    // remembers what both sides looked like after the last copy, so whichever side changed since then wins. saves that
+    // changed on both sides are left alone
+    fn pull(&mut self) {
+        for (name, lib, src) in self.library_pairs(true) {
+            let settled = self.conflicts.is_held(&src) || self.index.changed(&lib).is_none();
+            if is_library_extra(&lib) || self.paused.is_paused(&name) || settled {
+                continue;
+            }
+            if src.exists() {
+                if self.same_as_library(&src, &lib) {
+                    self.index.record(&lib);
+                    continue;
                 }
-                DebouncedEvent::Rename(a, b) => {
-                    log::info!("Rename {:?} -> {:?}", a, b);
+                if self.index.changed(&src).is_some() {
+                    match self.conflict(&name, &src, &lib) {
+                        Some(Keep::Local) => self.copy(src),
+                        Some(Keep::Library) => {
+                            let _err = self.pull_one(&name, &lib, &src);
+                        }
+                        None | Some(Keep::Cloud) => (),
+                    }
+                    continue;
                 }
-                _ => (),
-            },
-            Err(e) => log::info!("watch error: {:?}", e),
-        };
+                if self.busy(&src) {
+                    continue;
+                }
+            }
+            let _err = self.pull_one(&name, &lib, &src);
+        }
     }
-}
 
-// look for the path as registered in the save_map. both files and directories can be registered so if it's a directory
-// we need to chop off portions of the file path until we either find the path that the file was registered under or
-// get to the root (ie bad file). files under paths aren't registered, only find events when the dirs has an event
-fn find_appropriate_savedef_path(p: &PathBuf, save_map: &HashMap<PathBuf, SaveDef>) -> Result<PathBuf, String> {
-    let mut p = p.clone();
-    while !save_map.contains_key(&p) && p.parent() != None {
-        p.pop();
+    // cloud conflict copies made in the library by another computer syncing into it
+    fn find_cloud_conflicts(&mut self) {
+        for (name, cloud, src) in self.library_pairs(false) {
+            if let Some(lib) = conflicts::cloud_conflict_original(&cloud) {
+                self.cloud_conflict(&name, &cloud, &src, &lib);
+            }
+        }
     }
 
-    if !save_map.contains_key(&p) {
-        return Err("Path not in save map".to_string());
+    // a cloud service made the conflict copy @cloud of either the save @src or its library copy @lib. neither side
+    // knows which version is right so it's left for the conflicts command
+    fn cloud_conflict(&mut self, name: &str, cloud: &Path, src: &Path, lib: &Path) {
+        // the save may already be waiting on a conflict, this one is found again once that's resolved
+        if self.conflicts.has_cloud_copy(cloud) || self.conflicts.is_held(src) {
+            return;
+        }
+        log::warn!("found cloud conflict copy {:?}, leaving {:?} alone until it's resolved", cloud, src);
+        events::record("conflict", json!({
+            "tracker": name, "src": src, "dst": lib, "keep": null, "cloud_copy": cloud,
+            "cloud_sha256": helper::file_sha256(cloud).ok(),
+        }));
+        self.conflicts.add(Conflict {
+            tracker: name.to_string(),
+            save: src.to_path_buf(),
+            library: lib.to_path_buf(),
+            local_copy: None,
+            library_copy: None,
+            cloud_copy: Some(cloud.to_path_buf()),
+            time: chrono::Utc::now().timestamp(),
+        });
     }
 
-    Ok(p)
+    // copy the library save @lib over the local save @src
+    fn pull_one(&mut self, name: &str, lib: &Path, src: &Path) -> std::io::Result<u64> {
+        let result = self.get_library(lib, src);
+        match &result {
+            Ok(bytes) => {
+                log::info!("pulled {:?} from {:?}", src, lib);
+                let sha256 = self.index.record(src);
+                self.index.record(lib);
+                events::record("pull", json!({
+                    "tracker": name, "src": lib, "dst": src, "sha256": sha256, "bytes": bytes,
+                }));
+            }
+            Err(e) => {
+                log::error!("could not copy {:?} to {:?}: {:?}", lib, src, e);
+                events::record("fail", json!({
+                    "action": "pull", "tracker": name, "src": lib, "dst": src, "error": e.to_string(),
+                }));
+            }
+        }
+        result
+    }
+
+    // did the local save @src and the library save @lib both change since they were last synced
+    fn diverged(&mut self, src: &Path, lib: &Path) -> bool {
+        // nothing to compare against if the library save has never been seen, ie the first copy
+        if !lib.exists() || !self.index.known(lib) || self.index.changed(lib).is_none() {
+            return false;
+        }
+        self.index.changed(src).is_some() && !self.same_as_library(src, lib)
+    }
+
+    // @src and @lib changed on both sides. keeps whichever version loses as a conflict copy next to @lib and returns
+    // the side the tracker's conflict strategy picked, or None if it's left for the conflicts command
+    fn conflict(&mut self, name: &str, src: &Path, lib: &Path) -> Option<Keep> {
+        let strategy = find_save_by_name(name, &self.save_map).map_or(Strategy::Manual, |save| save.conflicts);
+        let keep = strategy.pick(src, lib);
+        let mut keep_copy = |from: &Path, side: &str| {
+            let to = conflicts::copy_path(lib, side);
+            match self.put_library(from, &to) {
+                Ok(_) => Some(to),
+                Err(e) => {
+                    log::error!("could not keep a conflict copy of {:?}: {:?}", from, e);
+                    None
+                }
+            }
+        };
+        let local_copy = if keep != Some(Keep::Local) { keep_copy(src, "local") } else { None };
+        let library_copy = if keep != Some(Keep::Library) { keep_copy(lib, "library") } else { None };
+        events::record("conflict", json!({
+            "tracker": name, "src": src, "dst": lib, "keep": keep.map(|k| k.name()),
+            "local_sha256": helper::file_sha256(src).ok(), "library_sha256": helper::file_sha256(lib).ok(),
+            "local_copy": local_copy, "library_copy": library_copy,
+        }));
+        match keep {
+            Some(keep) => log::warn!("{:?} changed here and in the library, keeping the {} version", src, keep.name()),
+            None => {
+                log::warn!("{:?} changed here and in the library, leaving both alone until it's resolved", src);
+                self.conflicts.add(Conflict {
+                    tracker: name.to_string(),
+                    save: src.to_path_buf(),
+                    library: lib.to_path_buf(),
+                    local_copy,
+                    library_copy,
+                    cloud_copy: None,
+                    time: chrono::Utc::now().timestamp(),
+                });
+            }
+        }
+        keep
+    }
+
+    // settle a conflict left for the conflicts command. the conflict copy of the version that's kept is removed since
+    // it's the same as the save now, a cloud conflict copy that isn't kept is renamed so it isn't found again
+    fn resolve(&mut self, p: &Path, keep: Keep) -> Result<String, String> {
+        let conflict = self.conflicts.take(p).ok_or_else(|| format!("no conflict for {:?}", p))?;
+        if keep == Keep::Cloud && conflict.cloud_copy.is_none() {
+            let err = format!("{:?} has no cloud conflict copy, keep local or library", conflict.save);
+            self.conflicts.add(conflict);
+            return Err(err);
+        }
+        let kept_copy = match keep {
+            Keep::Local => conflict.local_copy.clone(),
+            Keep::Library => conflict.library_copy.clone(),
+            Keep::Cloud => conflict.cloud_copy.clone(),
+        };
+        if let (Some(kept_copy), Keep::Cloud) = (&kept_copy, keep) {
+            // the cloud version becomes the library save and is pulled from there like a library one
+            if let Err(e) = self.put_library(kept_copy, &conflict.library) {
+                let err = format!("could not copy {:?} to {:?}: {}", kept_copy, conflict.library, e);
+                self.conflicts.add(conflict);
+                return Err(err);
+            }
+        }
+        // the conflict is only settled once the kept version is in place, until then it stays listed with every copy
+        let result = match keep {
+            Keep::Local => {
+                // the library version is the one being replaced, remember it so it isn't seen as a new conflict
+                self.index.record(&conflict.library);
+                self.copy(conflict.save.clone());
+                let journal = self.journal.lock().unwrap();
+                match journal.jobs().get(&conflict.save).and_then(|job| job.error.clone()) {
+                    Some(e) => Err(format!("could not copy {:?} to {:?}: {}", conflict.save, conflict.library, e)),
+                    None => Ok(()),
+                }
+            }
+            Keep::Library | Keep::Cloud => self.pull_one(&conflict.tracker, &conflict.library, &conflict.save)
+                .map(|_| ())
+                .map_err(|e| format!("could not copy {:?} to {:?}: {}", conflict.library, conflict.save, e)),
+        };
+        if let Err(e) = result {
+            self.conflicts.add(conflict);
+            return Err(e);
+        }
+        if let Some(kept_copy) = &kept_copy {
+            let _err = std::fs::remove_file(kept_copy);
+        }
+        if let (Some(cloud), Keep::Local | Keep::Library) = (&conflict.cloud_copy, keep) {
+            match conflicts::set_aside(cloud, &conflict.library) {
+                Ok(to) => log::info!("kept cloud conflict copy {:?} as {:?}", cloud, to),
+                Err(e) => log::error!("could not set aside cloud conflict copy {:?}: {:?}", cloud, e),
+            }
+        }
+        events::record("resolve", json!({
+            "tracker": conflict.tracker, "src": conflict.save, "dst": conflict.library, "keep": keep.name(),
+        }));
+        Ok(format!("kept the {} version of {:?}", keep.name(), conflict.save))
+    }
 }
 
 // thread function for file io heavy lifting
 fn save_watcher(
-    sync_dir: &str,
+    settings: PathBuf,
+    config: WatcherSettings,
     file_scan_tx: std::sync::mpsc::Sender<notify::DebouncedEvent>,
     file_op_tx: std::sync::mpsc::Sender<FileOpCmd>,
     file_op_rx: std::sync::mpsc::Receiver<FileOpCmd>,
+    paused: PauseState,
+    journal: Arc<Mutex<Journal>>,
 ) {
     let mut watcher = watcher(file_scan_tx, Duration::from_secs(1)).unwrap();
-    let mut save_map: HashMap<PathBuf, SaveDef> = HashMap::new();
+    let mut state = WatcherState {
+        save_map: HashMap::new(),
+        paused,
+        history: HashMap::new(),
+        journal,
+        index: StateIndex::load(),
+        catalogue: Catalogue::load(&config.sync_dir, &config.device),
+        conflicts: Conflicts::load(),
+        targets: vec![],
+        file_op_tx: file_op_tx.clone(),
+        settings: config,
+    };
+    let destinations = std::mem::take(&mut state.settings.destinations);
+    state.set_destinations(destinations);
+    state.init_git();
+    let mut last_pull = Instant::now();
     loop {
-        match file_op_rx.recv().unwrap() {
-            FileOpCmd::Watch(save) => {
-                print!("watch ");
-                save.print();
-                let p = save.path.clone();
-                if !p.exists() {
-                    log::warn!("{:?} doesn't exist", p);
+        // checked every time round, a steady stream of commands would keep the timeout below from ever happening
+        if last_pull.elapsed() >= PULL_INTERVAL {
+            state.find_cloud_conflicts();
+            state.pull();
+            if let Some(git) = state.settings.git.as_mut() {
+                git.push();
+            }
+            last_pull = Instant::now();
+        }
+        // wake up every so often to retry copies that failed
+        let cmd = match file_op_rx.recv_timeout(Duration::from_secs(1)) {
+            Ok(cmd) => cmd,
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                // nothing has been copied for a second, so whatever was copied before that goes in one commit
+                state.commit_library();
+                state.index.flush();
+                state.catalogue.flush();
+                let due = state.journal.lock().unwrap().due();
+                for src in due {
+                    log::info!("retrying {:?}", src);
+                    state.copy(src);
                 }
-                let _err = watcher.watch(&p, RecursiveMode::Recursive);
-                save_map.entry(p.clone()).or_insert(save);
-                // TODO: if err...
+                state.retry_destinations();
+                continue;
+            }
+            Err(mpsc::RecvTimeoutError::Disconnected) => break,
+        };
+        match cmd {
+            FileOpCmd::Watch(save) => {
+                watch_save(save, &mut watcher, &mut state.save_map);
             }
             FileOpCmd::Unwatch(_rmpath) => {
                 // watcher.unwatch(&entry).unwrap();
             }
             FileOpCmd::Copy(src) => {
-                let key = find_appropriate_savedef_path(&src, &save_map).unwrap();
-                let _err = format!("could not find {:?}", key);
-                let save_reg = save_map.get(&key).expect(&_err);
-                let sync_loc = PathBuf::from(save_reg.sync_loc.clone());
-                let has_appropriate_type = match &save_reg.options {
-                    SaveOpts::Dir(e) => { e.meets_rules(&src) },
-                    _ => true,
-                };
-
-                if has_appropriate_type {
-                    let mut dst = PathBuf::from(sync_dir);
-                    let (folder, fname) = helper::path_diff(key.clone(), src.clone());
-
-                    dst.push(sync_loc);
-                    dst.push(folder);
-
-                    std::fs::create_dir_all(&dst).expect("Could not create_dir_all");
-                    dst.push(fname);
-                    match src.extension() {
-                        Some(e) => dst.set_extension(e),
-                        _ => false,
-                    };
-
-                    match std::fs::copy(&src, &dst) {
-                        Err(e) => {
-                            log::info!("\nfile copy error: {:?} {:?} {:?}", e, src, dst);
-                            log::info!("{:?} exists: {:?}", src, src.exists());
-                            log::info!("{:?} exists: {:?}\n", dst, dst.exists());
-                            ()
-                        },
-                        _ => (),
+                state.copy(src);
+            }
+            FileOpCmd::Scan(name) => {
+                match name {
+                    Some(name) => match find_save_by_name(&name, &state.save_map) {
+                        Some(save) => scan_save(save, &file_op_tx),
+                        None => log::warn!("no tracker named {}", name),
+                    },
+                    None => {
+                        for save in state.save_map.values() {
+                            scan_save(save, &file_op_tx);
+                        }
                     }
                 }
             }
-            FileOpCmd::Scan() => {
-                for (key, _) in &save_map {
+            FileOpCmd::CatchUp() => {
+                let mut missed = 0;
+                for (key, save) in &state.save_map {
                     for entry in WalkDir::new(key).follow_links(true).into_iter().filter_map(|e| e.ok()) {
-                        let p = PathBuf::from(entry.path());
-                        if p.is_file() {
-                            file_op_tx.send(FileOpCmd::Copy(PathBuf::from(entry.path()))).unwrap();
+                        let p = entry.path().to_path_buf();
+                        if !p.is_file() {
+                            continue;
+                        }
+                        if let SaveOpts::Dir(d) = &save.options {
+                            if !d.meets_rules(&p) {
+                                continue;
+                            }
                         }
+                        let reason = match state.index.changed(&p) {
+                            Some(reason) => reason,
+                            None if !library_path(&state.settings.library, key, save, &p).exists() => "not in library",
+                            None => continue,
+                        };
+                        log::info!("missed while stopped ({}): {:?}", reason, p);
+                        events::record("change", json!({ "src": p, "kind": "missed", "reason": reason }));
+                        missed += 1;
+                        file_op_tx.send(FileOpCmd::Copy(p)).unwrap();
                     }
                 }
+                state.index.prune();
+                state.index.flush();
+                log::info!("{} saves changed while memurycard wasn't running", missed);
             }
-        }
-    }
-}
-
-// parse user generated json files indicating location of content storage areas
-fn parse_save_json(json_file: &str, save_accu: &mut Vec<SaveDef>) {
-    let bytes = std::fs::read_to_string(json_file).unwrap();
-    let json: Value = serde_json::from_str(&bytes).unwrap();
-    let saves = json["saves"].as_array().unwrap();
-
-    for i in 0 .. saves.len() {
-        // json elements with the "saves_path" field populated are directories
-        let mut path = PathBuf::new();
-        let name =  if saves[i]["name"] == Value::Null { "NO_NAME".to_string() }
-                    else { crate::helper::strip_quotes(saves[i]["name"].as_str().unwrap()) };
-        let sync_loc =  if saves[i]["sync_folder"] == Value::Null { PathBuf::from("") }
-                        else { PathBuf::from(crate::helper::strip_quotes(saves[i]["sync_folder"].as_str().unwrap())) };
-
-        let saveopt = if saves[i]["saves_path"] != Value::Null {
-            let dir = sanitize_slashes(&crate::helper::strip_quotes(saves[i]["saves_path"].as_str().unwrap()));
-            path.push(dir);
-            log::debug!("{:?}", path);
-            if saves[i]["allowed_filetypes"] != Value::Null && saves[i]["disallowed_filetypes"] != Value::Null {
-                log::error!("{:?} can only have an allow list or disallow list", path);
-                continue;
+            FileOpCmd::Status(reply) => {
+                let saves: Vec<&SaveDef> = state.save_map.values().collect();
+                let report = status::report(Some(std::process::id()), &state.settings.sync_dir, &saves, &state.paused,
+                    &state.journal, &state.index, &state.targets);
+                reply.send(report).unwrap();
             }
-
-            let rule_list = if saves[i]["allowed_filetypes"] == Value::Null && saves[i]["disallowed_filetypes"] == Value::Null {
-                log::debug!("providing empty disallow list for {:?}", path);
-                let empty_disallowed_vec: Vec<String> = vec![];
-                RuleList::Disallowed(empty_disallowed_vec)
-            } else if saves[i]["allowed_filetypes"] != Value::Null {
-                let allowed = saves[i]["allowed_filetypes"].as_array().unwrap();
-                let mut allowed_vec: Vec<String> = vec![];
-                for j in 0 .. allowed.len() {
-                    let filetypes_str = allowed[j].as_str().unwrap().to_string();
-                    if filetypes_str.len() > 0 {
-                        allowed_vec.push(filetypes_str);
+            FileOpCmd::List(reply) => {
+                let mut saves: Vec<&SaveDef> = state.save_map.values().collect();
+                saves.sort_by(|a, b| a.name.cmp(&b.name));
+                for save in saves {
+                    reply.send(format!("{} {:?} -> {:?}", save.name, save.path, save.sync_loc)).unwrap();
+                    if let SaveOpts::Dir(d) = &save.options {
+                        for line in d.rules_lines() {
+                            reply.send(format!("  {}", line)).unwrap();
+                        }
                     }
                 }
-                RuleList::Allowed(allowed_vec)
-            } else /* if saves[i]["disallowed_filetypes"] != Value::Null */ {
-                let disallowed = saves[i]["disallowed_filetypes"].as_array().unwrap();
-                let mut disallowed_vec: Vec<String> = vec![];
-                for j in 0..disallowed.len() {
-                    let disallowed_str = disallowed[j].as_str().unwrap().to_string();
-                    if disallowed_str.len() > 0 {
-                        disallowed_vec.push(disallowed_str);
+            }
+            FileOpCmd::Pause(name, reply) => {
+                match name {
+                    Some(name) if find_save_by_name(&name, &state.save_map).is_none() => {
+                        reply.send(format!("no tracker named {}", name)).unwrap();
+                    }
+                    Some(name) => {
+                        reply.send(format!("paused {}", name)).unwrap();
+                        state.paused.pause(Some(name));
+                    }
+                    None => {
+                        reply.send("paused all trackers".to_string()).unwrap();
+                        state.paused.pause(None);
                     }
                 }
-                RuleList::Disallowed(disallowed_vec)
-            };
-            let savedir = SaveDir {
-                rule_list: rule_list,
-            };
-            SaveOpts::Dir(savedir)
-        } else {
-            path.push(helper::strip_quotes(saves[i]["file"].as_str().unwrap()));
-            let savefile = SaveFile {
-            };
-            SaveOpts::File(savefile)
-        };
-        let savedef = SaveDef {
-            name: name,
-            path: path,
-            sync_loc: sync_loc,
-            options: saveopt,
-        };
-        save_accu.push(savedef);
-    }
-}
-
-// find all files in @json_dir that end in .json, return a vector of SaveDef's from them
-fn get_json_settings_descriptors(json_dir: &str) -> Vec<SaveDef> {
-    let json_dir = helper::strip_quotes(json_dir);
-    let mut save_accu: Vec<SaveDef> = vec![];
-
-    for entry in WalkDir::new(json_dir)
-        .follow_links(true)
-        .into_iter()
-        .filter_map(|e| e.ok())
-    {
-        let f_name = entry.file_name().to_string_lossy();
+            }
+            FileOpCmd::Resume(name, reply) => {
+                let paused = &mut state.paused;
+                match &name {
+                    Some(n) if !paused.paused_trackers().contains(n) && !paused.all_paused() => {
+                        reply.send(format!("{} is not paused", n)).unwrap();
+                        continue;
+                    }
+                    Some(n) if paused.all_paused() => {
+                        reply.send(format!("all trackers are paused, {} will stay paused until resume", n)).unwrap();
+                    }
+                    Some(n) => reply.send(format!("resumed {}", n)).unwrap(),
+                    None => reply.send("resumed all trackers".to_string()).unwrap(),
+                }
+                let ready = paused.resume(name);
+                if !ready.is_empty() {
+                    reply.send(format!("copying {} saves changed while paused", ready.len())).unwrap();
+                }
+                for p in ready {
+                    file_op_tx.send(FileOpCmd::Copy(p)).unwrap();
+                }
+            }
+            FileOpCmd::History(p, reply) => {
+                let p = PathBuf::from(sanitize_slashes(p.to_str().unwrap()));
+                // a git library has every version ever copied, not just the ones from this session
+                if let Some(git) = &state.settings.git {
+                    let lib = match state.library_copy(&p) {
+                        Some(lib) => lib,
+                        None => {
+                            reply.send(format!("{:?} isn't tracked or in the library", p)).unwrap();
+                            continue;
+                        }
+                    };
+                    match git.log(&lib) {
+                        Ok(commits) if commits.is_empty() => {
+                            reply.send(format!("no commits of {:?}", lib)).unwrap();
+                        }
+                        Ok(commits) => {
+                            for commit in commits {
+                                let time = chrono::Local.timestamp_opt(commit.time, 0).single()
+                                    .map_or(String::new(), |t| t.format("%Y-%m-%d %H:%M:%S").to_string());
+                                reply.send(format!("{} {} {}", time, &commit.id[..12.min(commit.id.len())],
+                                    commit.subject)).unwrap();
+                            }
+                        }
+                        Err(e) => reply.send(format!("could not read the history of {:?}: {}", lib, e)).unwrap(),
+                    }
+                    continue;
+                }
+                for (src, records) in &state.history {
+                    for record in records {
+                        if *src == p || record.dst == p {
+                            reply.send(format!("{} {:?} -> {:?}",
+                                record.time.format("%Y-%m-%d %H:%M:%S"), src, record.dst)).unwrap();
+                        }
+                    }
+                }
+            }
+            FileOpCmd::Restore(p, revision, reply) => {
+                let src = PathBuf::from(sanitize_slashes(p.to_str().unwrap()));
+                let key = match find_appropriate_savedef_path(&src, &state.save_map) {
+                    Ok(key) => key,
+                    Err(e) => {
+                        reply.send(format!("{}: {:?}", e, src)).unwrap();
+                        continue;
+                    }
+                };
+                let save_reg = &state.save_map[&key];
+                let name = save_reg.name.clone();
+                // with a library per computer the newest copy from any of them is the one worth restoring
+                let root = state.settings.latest.as_ref().unwrap_or(&state.settings.library);
+                let dst = library_path(root, &key, save_reg, &src);
+                // an older version is taken out of git next to the library copy and restored from there
+                let from = match (&revision, &state.settings.git) {
+                    (None, _) if !dst.exists() => {
+                        reply.send(format!("no library copy of {:?}", src)).unwrap();
+                        continue;
+                    }
+                    (None, _) => dst.clone(),
+                    (Some(_), None) => {
+                        reply.send("the library isn't a git repository, there are no older versions".to_string())
+                            .unwrap();
+                        continue;
+                    }
+                    (Some(id), Some(git)) => {
+                        let tmp = helper::temp_path(&dst);
+                        let shown = dst.parent().map_or(Ok(()), std::fs::create_dir_all)
+                            .and_then(|_| git.show(id, &dst, &tmp));
+                        if let Err(e) = shown {
+                            let _err = std::fs::remove_file(&tmp);
+                            reply.send(format!("could not get {:?} from commit {}: {}", dst, id, e)).unwrap();
+                            continue;
+                        }
+                        tmp
+                    }
+                };
 
-        if f_name.ends_with(".json") {
-            let entry = entry.path().to_str().unwrap();
-            parse_save_json(&entry, &mut save_accu);
+                // keep whatever is being overwritten next to the library copy so a bad restore can be undone
+                let mut backup = None;
+                if src.exists() {
+                    let mut path = dst.clone().into_os_string();
+                    path.push(".before-restore");
+                    if let Err(e) = state.put_library(&src, Path::new(&path)) {
+                        if revision.is_some() {
+                            let _err = std::fs::remove_file(&from);
+                        }
+                        reply.send(format!("could not back up {:?}: {:?}", src, e)).unwrap();
+                        continue;
+                    }
+                    backup = Some(PathBuf::from(path));
+                }
+                let replaced_sha256 = backup.as_ref().and_then(|b| helper::file_sha256(b).ok());
+                let restored = match &revision {
+                    Some(_) => helper::copy_atomic(&from, &src),
+                    None => state.get_library(&dst, &src),
+                };
+                if revision.is_some() {
+                    let _err = std::fs::remove_file(&from);
+                }
+                match restored {
+                    Ok(_) => {
+                        events::record("restore", json!({
+                            "tracker": name, "src": dst, "dst": src, "revision": revision,
+                            "sha256": helper::file_sha256(&src).ok(), "replaced_sha256": replaced_sha256,
+                            "backup": backup,
+                        }));
+                        match &revision {
+                            Some(id) => reply.send(format!("restored {:?} from {:?} in commit {}", src, dst, id)),
+                            None => reply.send(format!("restored {:?} from {:?}", src, dst)),
+                        }.unwrap();
+                    }
+                    Err(e) => {
+                        events::record("fail", json!({
+                            "action": "restore", "tracker": name, "src": dst, "dst": src,
+                            "error": e.to_string(),
+                        }));
+                        reply.send(format!("could not restore {:?}: {:?}", src, e)).unwrap();
+                    }
+                }
+            }
+            FileOpCmd::Reload(reply) => {
+                match WatcherSettings::load(&settings) {
+                    Ok(mut config) => {
+                        state.paused.set_mode(config.pause_mode);
+                        state.set_destinations(std::mem::take(&mut config.destinations));
+                        // copies that haven't been committed yet go in the old repository
+                        if let Some(git) = state.settings.git.as_mut() {
+                            if let Err(e) = git.commit() {
+                                log::warn!("could not commit the library: {}", e);
+                            }
+                        }
+                        if config.sync_dir != state.settings.sync_dir {
+                            state.catalogue.flush();
+                            state.catalogue = Catalogue::load(&config.sync_dir, &config.device);
+                        }
+                        state.settings = config;
+                        state.init_git();
+                    }
+                    Err(e) => reply.send(format!("{}, keeping the old settings", e)).unwrap(),
+                }
+                for p in state.save_map.keys() {
+                    let _err = watcher.unwatch(p);
+                }
+                state.save_map.clear();
+                for save in get_json_settings_descriptors(&state.settings.json_dir) {
+                    watch_save(save, &mut watcher, &mut state.save_map);
+                }
+                let save_map = &state.save_map;
+                state.paused.retain(|name| find_save_by_name(name, save_map).is_some());
+                reply.send(format!("reloaded {} trackers", state.save_map.len())).unwrap();
+            }
+            FileOpCmd::Conflicts(reply) => {
+                for line in state.conflicts.lines() {
+                    reply.send(line).unwrap();
+                }
+            }
+            FileOpCmd::Resolve(p, keep, reply) => {
+                let p = PathBuf::from(sanitize_slashes(p.to_str().unwrap()));
+                match state.resolve(&p, keep) {
+                    Ok(line) | Err(line) => reply.send(line).unwrap(),
+                }
+            }
+            FileOpCmd::Sent(sent) => {
+                state.sent(sent);
+            }
+            FileOpCmd::Received(received) => {
+                let _err = received.reply.send(state.received(&received));
+            }
+            FileOpCmd::Quit() => {
+                log::info!("shutting down");
+                // stop picking up new changes, then finish the copies that were already asked for
+                for p in state.save_map.keys() {
+                    let _err = watcher.unwatch(p);
+                }
+                // copies to destinations still on their way stay in the destination's journal for next time
+                while let Ok(cmd) = file_op_rx.try_recv() {
+                    match cmd {
+                        FileOpCmd::Copy(src) => state.copy(src),
+                        FileOpCmd::Sent(sent) => state.sent(sent),
+                        FileOpCmd::Received(received) => {
+                            let _err = received.reply.send(state.received(&received));
+                        }
+                        // anyone waiting on an answer gets one instead of a closed channel
+                        FileOpCmd::List(reply)
+                        | FileOpCmd::Pause(_, reply)
+                        | FileOpCmd::Resume(_, reply)
+                        | FileOpCmd::History(_, reply)
+                        | FileOpCmd::Restore(_, _, reply)
+                        | FileOpCmd::Reload(reply)
+                        | FileOpCmd::Conflicts(reply)
+                        | FileOpCmd::Resolve(_, _, reply) => {
+                            let _err = reply.send("shutting down".to_string());
+                        }
+                        _ => (),
+                    }
+                }
+                if let Some(git) = state.settings.git.as_mut() {
+                    if let Err(e) = git.commit() {
+                        log::warn!("could not commit the library: {}", e);
+                    }
+                    git.finish_push(true);
+                }
+                state.index.flush();
+                state.catalogue.flush();
+                control::cleanup();
+                instance::release();
+                console::restore_terminal();
+                log::info!("exit");
+                std::process::exit(0);
+            }
         }
     }
-    save_accu
 }
 
 // crawl through saves listed from save files and send results to watcher thread
@@ -344,29 +1187,81 @@ fn find_json_settings(json_dir: &str, file_op_tx: &mpsc::Sender<FileOpCmd>) {
     }
 }
 
-pub fn run() {
-    let args = Cli::from_args();
-    let parse = crate::helper::parse_json(&args.settings).unwrap();
-    let tracker_dir = "trackers".to_string(); // sanitize_slashes(&parse["tracker_dir"].to_string());
+pub fn run(settings: &PathBuf, background: bool) {
+    // running twice means two watchers copying the same files into the same place, hand off to the one that's
+    // already running instead
+    if let Err(pid) = instance::acquire() {
+        match pid {
+            Some(pid) => log::info!("memurycard is already running (pid {})", pid),
+            None => log::info!("memurycard is already running"),
+        }
+        match control::call("scan", serde_json::json!({})) {
+            Ok(_) => log::info!("asked it to scan for saves"),
+            Err(e) => log::warn!("could not reach it: {}", e),
+        }
+        return;
+    }
 
-    let sync_dir = sanitize_slashes(&crate::helper::strip_quotes(&parse["sync_path"].to_string()));
+    let mut config = match WatcherSettings::load(settings) {
+        Ok(config) => config,
+        Err(e) => {
+            log::error!("{}", e);
+            instance::release();
+            return;
+        }
+    };
+    log::info!("device {} ({})", config.device.name, config.device.id);
+    let peers = config.peers.take();
+    let paused = PauseState::load(config.pause_mode);
+    let journal = Arc::new(Mutex::new(Journal::load()));
+    let journal2 = journal.clone();
+    let settings = settings.clone();
 
     let (file_scan_tx, file_scan_rx) = mpsc::channel();
     let (file_op_tx, file_op_rx) = mpsc::channel();
     let file_op_tx2 = file_op_tx.clone();
     let file_op_tx3 = file_op_tx.clone();
+    let file_op_tx4 = file_op_tx.clone();
+    let file_op_tx5 = file_op_tx.clone();
+    let file_op_tx6 = file_op_tx.clone();
+
+    find_json_settings(&config.json_dir, &file_op_tx);
+    // copies that hadn't finished when we last stopped
+    for p in journal.lock().unwrap().pending() {
+        file_op_tx.send(FileOpCmd::Copy(p)).unwrap();
+    }
+    file_op_tx.send(FileOpCmd::CatchUp()).unwrap();
 
     let save_scanner_handle = thread::spawn(move || {
-        save_scanner(file_scan_rx, &file_op_tx);
+        save_scanner(file_scan_rx, &file_op_tx, &journal);
     });
     let save_watcher_handle = thread::spawn(move || {
-        save_watcher(&sync_dir, file_scan_tx, file_op_tx3, file_op_rx);
+        save_watcher(settings, config, file_scan_tx, file_op_tx3, file_op_rx, paused, journal2);
+    });
+    let control_handle = thread::spawn(move || {
+        control::serve(file_op_tx4);
     });
-    let interactive_handle = thread::spawn(move || {
-        interactive(&tracker_dir, &file_op_tx2);
+    let signals_handle = thread::spawn(move || {
+        signals::handle(&file_op_tx5);
     });
+    let peers_handle = peers.map(|peers| thread::spawn(move || peer::run(peers, file_op_tx6)));
+    // there's no terminal to read commands from in the background, use the control socket instead
+    let interactive_handle = if background {
+        None
+    } else {
+        Some(thread::spawn(move || {
+            console::interactive(&file_op_tx2);
+        }))
+    };
 
     save_scanner_handle.join().unwrap();
     save_watcher_handle.join().unwrap();
-    interactive_handle.join().unwrap();
+    if let Some(interactive_handle) = interactive_handle {
+        interactive_handle.join().unwrap();
+    }
+    control_handle.join().unwrap();
+    signals_handle.join().unwrap();
+    if let Some(peers_handle) = peers_handle {
+        peers_handle.join().unwrap();
+    }
 }
@@ -0,0 +1,419 @@
+// destinations on a server reached over ssh. the sftp program that comes with openssh does the work so keys, agents
+// and known_hosts behave the same as they do for ssh:
+// {"name": "home", "type": "sftp", "host": "nas.local", "port": 22, "user": "alex", "key": "~/.ssh/id_ed25519",
+//  "root": "/srv/saves"}
+// "ssh_options" adds -o options, ie ["UserKnownHostsFile=/path"], and "program" runs a different sftp
+use crate::helper;
+use crate::service::storage::{Stat, Storage};
+use serde_json::Value;
+use std::io::{Error, ErrorKind, Result, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+// sftp gives up on a server that doesn't answer after this many seconds
+const CONNECT_TIMEOUT: u32 = 15;
+// connections that fail are tried this many times before the copy is left to the journal to retry
+const CONNECT_TRIES: u32 = 3;
+const CONNECT_RETRY_WAIT: Duration = Duration::from_secs(2);
+// ssh exits with this when it couldn't connect or the connection dropped, sftp passes it on
+const SSH_FAILED: i32 = 255;
+
+pub struct SftpStorage {
+    host: String,
+    port: u16,
+    user: Option<String>,
+    key: Option<PathBuf>,
+    root: String,
+    program: String,
+    options: Vec<String>,
+}
+
+// ~/ at the start of @p is the home folder, like in a shell
+fn expand_home(p: &str) -> PathBuf {
+    let home = std::env::var("HOME").or_else(|_| std::env::var("USERPROFILE"));
+    match (p.strip_prefix("~/"), home) {
+        (Some(rest), Ok(home)) => PathBuf::from(home).join(rest),
+        _ => PathBuf::from(p),
+    }
+}
+
+// sftp splits batch lines on spaces and expands wildcards in remote paths, so every path goes in quotes with
+// anything special escaped
+fn quote(p: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in p.chars() {
+        if matches!(c, '"' | '\\' | '*' | '?' | '[' | ']') {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    quoted
+}
+
+// seconds since 1970 from the date columns of ls -l, ie "Oct 19 07:06" this year or "Oct 19 2024" for older files
+fn parse_ls_time(month: &str, day: &str, time_or_year: &str) -> i64 {
+    use chrono::{Datelike, TimeZone};
+    let now = chrono::Local::now();
+    let (year, time) = match time_or_year.parse::<i32>() {
+        Ok(year) => (year, "00:00"),
+        Err(_) => (now.year(), time_or_year),
+    };
+    let text = format!("{} {} {} {}", year, month, day, time);
+    chrono::NaiveDateTime::parse_from_str(&text, "%Y %b %d %H:%M").ok()
+        .and_then(|t| chrono::Local.from_local_datetime(&t).earliest())
+        .map(|t| {
+            // a time without a year that's in the future was last year, ls only leaves out the year for recent files
+            if t > now { t.with_year(year - 1).unwrap_or(t) } else { t }
+        })
+        .map_or(0, |t| t.timestamp())
+}
+
+// a line of ls -ln, None for anything else
+struct Listed {
+    dir: bool,
+    size: u64,
+    modified: i64,
+    name: String,
+}
+
+fn parse_ls_line(line: &str) -> Option<Listed> {
+    let mut rest = line.trim_start();
+    let mut fields = vec![];
+    // permissions, links, uid, gid, size, month, day, time or year, then the name which can hold spaces
+    for _ in 0..8 {
+        let end = rest.find(char::is_whitespace)?;
+        fields.push(&rest[..end]);
+        rest = rest[end..].trim_start();
+    }
+    if rest.is_empty() || !fields[0].starts_with(['-', 'd', 'l']) {
+        return None;
+    }
+    Some(Listed {
+        dir: fields[0].starts_with('d'),
+        size: fields[4].parse().ok()?,
+        modified: parse_ls_time(fields[5], fields[6], fields[7]),
+        // sftp prints the path it was given in front of each name
+        name: rest.rsplit('/').next().unwrap_or(rest).to_string(),
+    })
+}
+
+impl SftpStorage {
+    pub fn from_settings(d: &Value) -> std::result::Result<SftpStorage, String> {
+        let host = match d["host"].as_str() {
+            Some(host) if !host.is_empty() => host.to_string(),
+            _ => return Err("sftp destinations need a host".to_string()),
+        };
+        let root = match d["root"].as_str() {
+            Some(root) if !root.is_empty() => root.trim_end_matches('/').to_string(),
+            _ => return Err("sftp destinations need a root folder on the server".to_string()),
+        };
+        let port = match &d["port"] {
+            Value::Null => 22,
+            port => port.as_u64().filter(|p| *p <= u16::MAX as u64).map(|p| p as u16)
+                .ok_or_else(|| format!("bad sftp port {}", port))?,
+        };
+        Ok(SftpStorage {
+            host,
+            port,
+            user: d["user"].as_str().map(|u| u.to_string()),
+            key: d["key"].as_str().map(expand_home),
+            root,
+            program: d["program"].as_str().unwrap_or("sftp").to_string(),
+            options: d["ssh_options"].as_array().into_iter().flatten()
+                .filter_map(|o| o.as_str())
+                .map(|o| o.to_string())
+                .collect(),
+        })
+    }
+
+    fn remote(&self, key: &str) -> String {
+        format!("{}/{}", self.root, key)
+    }
+
+    fn destination(&self) -> String {
+        match &self.user {
+            Some(user) => format!("{}@{}", user, self.host),
+            None => self.host.clone(),
+        }
+    }
+
+    fn command(&self) -> Command {
+        let mut cmd = Command::new(&self.program);
+        cmd.arg("-q").arg("-b").arg("-").arg("-P").arg(self.port.to_string());
+        // never stop to ask for a password or about an unknown host, there's nobody to answer
+        cmd.arg("-o").arg("BatchMode=yes");
+        cmd.arg("-o").arg(format!("ConnectTimeout={}", CONNECT_TIMEOUT));
+        if let Some(key) = &self.key {
+            cmd.arg("-i").arg(key);
+        }
+        for option in &self.options {
+            cmd.arg("-o").arg(option);
+        }
+        cmd.arg(self.destination());
+        cmd.stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::piped());
+        cmd
+    }
+
+    // run sftp commands, one per line. a command starting with - can fail without stopping the rest. returns what
+    // sftp printed, which includes each command after "sftp> "
+    fn run(&self, batch: &str) -> Result<String> {
+        self.run_tries(batch, CONNECT_TRIES)
+    }
+
+    // run @batch, connecting up to @max_tries times
+    fn run_tries(&self, batch: &str, max_tries: u32) -> Result<String> {
+        let mut tries = 0;
+        loop {
+            tries += 1;
+            let mut child = self.command().spawn()
+                .map_err(|e| Error::new(e.kind(), format!("could not run {}: {}", self.program, e)))?;
+            child.stdin.take().unwrap().write_all(batch.as_bytes())?;
+            let output = child.wait_with_output()?;
+            if output.status.success() {
+                return Ok(String::from_utf8_lossy(&output.stdout).to_string());
+            }
+            let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();
+            if output.status.code() == Some(SSH_FAILED) && tries < max_tries {
+                log::warn!("could not reach {} ({}), trying again", self.host, stderr);
+                std::thread::sleep(CONNECT_RETRY_WAIT);
+                continue;
+            }
+            let kind = match output.status.code() {
+                Some(SSH_FAILED) => ErrorKind::NotConnected,
+                _ => ErrorKind::Other,
+            };
+            return Err(Error::new(kind, format!("sftp to {} failed: {}", self.host, stderr)));
+        }
+    }
+
+    // files and folders directly in each of @dirs, in the same order
+    fn ls(&self, dirs: &[String]) -> Result<Vec<Vec<Listed>>> {
+        let batch: String = dirs.iter().map(|dir| format!("-ls -ln {}\n", quote(dir))).collect();
+        let output = self.run(&batch)?;
+        let mut listings: Vec<Vec<Listed>> = vec![];
+        for line in output.lines() {
+            if line.starts_with("sftp>") {
+                listings.push(vec![]);
+            } else if let (Some(listing), Some(listed)) = (listings.last_mut(), parse_ls_line(line)) {
+                listing.push(listed);
+            }
+        }
+        listings.resize_with(dirs.len(), Vec::new);
+        Ok(listings)
+    }
+}
+
+impl Storage for SftpStorage {
+    fn location(&self) -> String {
+        format!("sftp://{}:{}{}", self.destination(), self.port, self.root)
+    }
+
+    // ls only gives sizes and times to the minute, that's not enough to know a file is the same
+    fn hashes(&self) -> bool {
+        false
+    }
+
+    // uploaded under a temporary name then renamed over the old file, openssh servers replace it in one step
+    fn put(&mut self, key: &str, src: &Path) -> Result<u64> {
+        let size = std::fs::metadata(src)?.len();
+        let dst = self.remote(key);
+        let tmp = format!("{}.memurycard-tmp", dst);
+        let mut batch = String::new();
+        let mut dir = self.root.clone();
+        for part in key.split('/').collect::<Vec<_>>().split_last().map_or(&[][..], |(_, dirs)| dirs) {
+            dir.push('/');
+            dir.push_str(part);
+            batch.push_str(&format!("-mkdir {}\n", quote(&dir)));
+        }
+        batch.push_str(&format!("put {} {}\n", quote(&src.to_string_lossy()), quote(&tmp)));
+        batch.push_str(&format!("rename {} {}\n", quote(&tmp), quote(&dst)));
+        if let Err(e) = self.run(&batch) {
+            // a server that couldn't be reached won't be for the clean up either. a temporary file left behind is
+            // replaced by the next upload and never listed
+            if e.kind() != ErrorKind::NotConnected {
+                let _err = self.run_tries(&format!("-rm {}\n", quote(&tmp)), 1);
+            }
+            return Err(e);
+        }
+        Ok(size)
+    }
+
+    fn get(&mut self, key: &str, dst: &Path) -> Result<u64> {
+        if let Some(parent) = dst.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let tmp = helper::temp_path(dst);
+        let result = self.run(&format!("get {} {}\n", quote(&self.remote(key)), quote(&tmp.to_string_lossy())))
+            .and_then(|_| std::fs::rename(&tmp, dst));
+        if let Err(e) = result {
+            let _err = std::fs::remove_file(&tmp);
+            return Err(e);
+        }
+        Ok(std::fs::metadata(dst)?.len())
+    }
+
+    fn stat(&mut self, key: &str) -> Result<Option<Stat>> {
+        let remote = self.remote(key);
+        let name = key.rsplit('/').next().unwrap_or(key);
+        let listing = self.ls(&[remote])?.pop().unwrap_or_default();
+        Ok(listing.into_iter()
+            .find(|l| !l.dir && l.name == name)
+            .map(|l| Stat { size: l.size, modified: l.modified, sha256: None }))
+    }
+
+    // one sftp session per level of folders
+    fn list(&mut self, prefix: &str) -> Result<Vec<String>> {
+        let mut keys = vec![];
+        let mut level = vec![String::new()];
+        while !level.is_empty() {
+            let dirs: Vec<String> = level.iter()
+                .map(|dir| if dir.is_empty() { self.root.clone() } else { self.remote(dir) })
+                .collect();
+            let mut next = vec![];
+            for (dir, listing) in level.iter().zip(self.ls(&dirs)?) {
+                for l in listing {
+                    if l.name == "." || l.name == ".." || l.name.ends_with(".memurycard-tmp") {
+                        continue;
+                    }
+                    let key = if dir.is_empty() { l.name } else { format!("{}/{}", dir, l.name) };
+                    // skip folders that can't hold anything under the prefix
+                    if l.dir && (key.starts_with(prefix) || prefix.starts_with(&format!("{}/", key))) {
+                        next.push(key);
+                    } else if !l.dir && key.starts_with(prefix) {
+                        keys.push(key);
+                    }
+                }
+            }
+            level = next;
+        }
+        keys.sort();
+        Ok(keys)
+    }
+
+    fn delete(&mut self, key: &str) -> Result<()> {
+        self.run(&format!("rm {}\n", quote(&self.remote(key)))).map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Datelike, TimeZone};
+
+    fn local(year: i32, month: u32, day: u32, hour: u32, min: u32) -> i64 {
+        chrono::Local.with_ymd_and_hms(year, month, day, hour, min, 0).earliest().unwrap().timestamp()
+    }
+
+    #[test]
+    fn quotes_paths() {
+        assert_eq!(quote("/srv/saves/a.sav"), "\"/srv/saves/a.sav\"");
+        assert_eq!(quote("/srv/we ird[1].sav"), "\"/srv/we ird\\[1\\].sav\"");
+        assert_eq!(quote("a*b?.sav"), "\"a\\*b\\?.sav\"");
+        assert_eq!(quote("say \"hi\"\\"), "\"say \\\"hi\\\"\\\\\"");
+    }
+
+    #[test]
+    fn ls_time_with_year() {
+        assert_eq!(parse_ls_time("Oct", "19", "2024"), local(2024, 10, 19, 0, 0));
+        assert_eq!(parse_ls_time("Feb", "3", "1999"), local(1999, 2, 3, 0, 0));
+        assert_eq!(parse_ls_time("Foo", "3", "1999"), 0);
+    }
+
+    #[test]
+    fn ls_time_without_year() {
+        // ls leaves the year out for files from the last six months
+        let then = chrono::Local::now() - chrono::Duration::days(1);
+        let month = then.format("%b").to_string();
+        assert_eq!(parse_ls_time(&month, &then.day().to_string(), "07:06"),
+            local(then.year(), then.month(), then.day(), 7, 6));
+    }
+
+    #[test]
+    fn ls_lines() {
+        let file = parse_ls_line("-rw-r--r--    1 1000     1000         1234 Oct 19  2024 /srv/game/we ird[1].sav")
+            .unwrap();
+        assert!(!file.dir);
+        assert_eq!(file.size, 1234);
+        assert_eq!(file.modified, local(2024, 10, 19, 0, 0));
+        assert_eq!(file.name, "we ird[1].sav");
+
+        let dir = parse_ls_line("drwxr-xr-x    2 0        0            4096 Jan  1  2024 /srv/game").unwrap();
+        assert!(dir.dir);
+        assert_eq!(dir.name, "game");
+
+        assert!(parse_ls_line("sftp> -ls -ln \"/srv/game\"").is_none());
+        assert!(parse_ls_line("Can't ls: \"/srv/nothing\" not found").is_none());
+        assert!(parse_ls_line("").is_none());
+    }
+
+    // a storage whose sftp is a script that writes down how it was run and the batch it was given, then exits with
+    // @code
+    #[cfg(unix)]
+    fn fake_sftp(name: &str, code: i32) -> (SftpStorage, PathBuf) {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = std::env::temp_dir().join(format!("memurycard-sftp-{}-{}", name, std::process::id()));
+        let _err = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let script = dir.join("sftp");
+        std::fs::write(&script, format!("#!/bin/sh\necho \"$@\" >> \"{0}/args\"\ncat >> \"{0}/batch\"\nexit {1}\n",
+            dir.display(), code)).unwrap();
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let settings = serde_json::json!({
+            "host": "nas.local", "port": 2222, "user": "alex", "root": "/srv/saves/", "program": script,
+        });
+        (SftpStorage::from_settings(&settings).unwrap(), dir)
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn put_batch() {
+        let (mut storage, dir) = fake_sftp("put", 0);
+        let src = dir.join("a.srm");
+        std::fs::write(&src, b"save").unwrap();
+        assert_eq!(storage.put("snes/zelda/a.srm", &src).unwrap(), 4);
+
+        let args = std::fs::read_to_string(dir.join("args")).unwrap();
+        assert!(args.starts_with("-q -b - -P 2222 -o BatchMode=yes"));
+        assert!(args.trim_end().ends_with("alex@nas.local"));
+        let batch = std::fs::read_to_string(dir.join("batch")).unwrap();
+        assert_eq!(batch, format!(concat!(
+            "-mkdir \"/srv/saves/snes\"\n",
+            "-mkdir \"/srv/saves/snes/zelda\"\n",
+            "put \"{}\" \"/srv/saves/snes/zelda/a.srm.memurycard-tmp\"\n",
+            "rename \"/srv/saves/snes/zelda/a.srm.memurycard-tmp\" \"/srv/saves/snes/zelda/a.srm\"\n"),
+            src.display()));
+        let _err = std::fs::remove_dir_all(&dir);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn put_failed() {
+        // anything but a connection failure is given up on straight away and the temporary file cleaned up
+        let (mut storage, dir) = fake_sftp("failed", 1);
+        let src = dir.join("a.srm");
+        std::fs::write(&src, b"save").unwrap();
+        let e = storage.put("a.srm", &src).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::Other);
+        let batch = std::fs::read_to_string(dir.join("batch")).unwrap();
+        assert!(batch.ends_with("-rm \"/srv/saves/a.srm.memurycard-tmp\"\n"));
+        let _err = std::fs::remove_dir_all(&dir);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn put_not_connected() {
+        // ssh's 255 is tried CONNECT_TRIES times and then leaves the destination alone, cleaning up included
+        let (mut storage, dir) = fake_sftp("offline", SSH_FAILED);
+        let src = dir.join("a.srm");
+        std::fs::write(&src, b"save").unwrap();
+        let e = storage.put("a.srm", &src).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::NotConnected);
+        let runs = std::fs::read_to_string(dir.join("args")).unwrap().lines().count();
+        assert_eq!(runs, CONNECT_TRIES as usize);
+        let batch = std::fs::read_to_string(dir.join("batch")).unwrap();
+        assert!(!batch.contains("-rm"));
+        let _err = std::fs::remove_dir_all(&dir);
+    }
+}
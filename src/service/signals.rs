@@ -0,0 +1,36 @@
+// SIGTERM and SIGINT shut down the same way quit does so copies aren't cut off halfway, SIGHUP reloads the settings and
+// tracker files like the reload command
+use crate::service::service::FileOpCmd;
+use std::sync::mpsc;
+
+#[cfg(unix)]
+pub fn handle(file_op_tx: &mpsc::Sender<FileOpCmd>) {
+    use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+    use signal_hook::iterator::Signals;
+
+    let mut signals = match Signals::new([SIGTERM, SIGINT, SIGHUP]) {
+        Ok(signals) => signals,
+        Err(e) => {
+            log::error!("could not register signal handlers: {:?}", e);
+            return;
+        }
+    };
+    for signal in signals.forever() {
+        if signal == SIGHUP {
+            log::info!("SIGHUP, reloading");
+            let (reply_tx, reply_rx) = mpsc::channel();
+            file_op_tx.send(FileOpCmd::Reload(reply_tx)).unwrap();
+            for line in reply_rx {
+                log::info!("{}", line);
+            }
+        } else {
+            log::info!("signal {}, shutting down", signal);
+            file_op_tx.send(FileOpCmd::Quit()).unwrap();
+        }
+    }
+}
+
+// ctrl-c on windows still terminates immediately, copies are atomic so the library is never left half written
+#[cfg(not(unix))]
+pub fn handle(_file_op_tx: &mpsc::Sender<FileOpCmd>) {
+}
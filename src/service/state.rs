@@ -0,0 +1,114 @@
+// what every tracked save looked like the last time it was copied, so a restart only has to copy what changed while
+// memury card wasn't running instead of everything
+use crate::helper;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+static STATE_FILE: &str = "scary/state.json";
+
+#[derive(Serialize, Deserialize)]
+pub struct FileState {
+    pub mtime_secs: u64,
+    pub mtime_nanos: u32,
+    pub size: u64,
+    pub sha256: String,
+    // unix time of the last copy, older state files don't have it
+    #[serde(default)]
+    pub copied: i64,
+}
+
+pub struct StateIndex {
+    path: PathBuf,
+    files: BTreeMap<PathBuf, FileState>,
+    dirty: bool,
+}
+
+// mtime and size without reading the file
+fn stat(p: &Path) -> std::io::Result<(u64, u32, u64)> {
+    let meta = std::fs::metadata(p)?;
+    let mtime = meta.modified()?.duration_since(UNIX_EPOCH).unwrap_or_default();
+    Ok((mtime.as_secs(), mtime.subsec_nanos(), meta.len()))
+}
+
+impl StateIndex {
+    pub fn load() -> StateIndex {
+        let path = PathBuf::from(STATE_FILE);
+        let files = match std::fs::read_to_string(&path) {
+            Ok(s) => serde_json::from_str(&s).unwrap_or_else(|e| {
+                log::warn!("could not parse {:?}, everything will be copied again: {:?}", path, e);
+                BTreeMap::new()
+            }),
+            Err(_) => BTreeMap::new(),
+        };
+        StateIndex { path, files, dirty: false }
+    }
+
+    pub fn flush(&mut self) {
+        if !self.dirty {
+            return;
+        }
+        if let Some(parent) = self.path.parent() {
+            let _err = std::fs::create_dir_all(parent);
+        }
+        match std::fs::write(&self.path, serde_json::to_string(&self.files).unwrap()) {
+            Ok(_) => self.dirty = false,
+            Err(e) => log::error!("could not save {:?}: {:?}", self.path, e),
+        }
+    }
+
+    // has @p ever been recorded
+    pub fn known(&self, p: &Path) -> bool {
+        self.files.contains_key(p)
+    }
+
+    // remember what @p looks like now, after it's been copied. returns its hash
+    pub fn record(&mut self, p: &Path) -> Option<String> {
+        let (mtime_secs, mtime_nanos, size) = stat(p).ok()?;
+        let sha256 = helper::file_sha256(p).ok()?;
+        let copied = chrono::Utc::now().timestamp();
+        self.files.insert(p.to_path_buf(), FileState { mtime_secs, mtime_nanos, size, sha256: sha256.clone(), copied });
+        self.dirty = true;
+        Some(sha256)
+    }
+
+    // has @p changed since it was last recorded. returns why if it has. the hash is only checked when the mtime or size
+    // are different, and if the contents turn out to be the same the new mtime is remembered so it isn't hashed again
+    pub fn changed(&mut self, p: &Path) -> Option<&'static str> {
+        let (mtime_secs, mtime_nanos, size) = match stat(p) {
+            Ok(s) => s,
+            Err(_) => return None,
+        };
+        let known = match self.files.get_mut(p) {
+            Some(known) => known,
+            None => return Some("new"),
+        };
+        if known.mtime_secs == mtime_secs && known.mtime_nanos == mtime_nanos && known.size == size {
+            return None;
+        }
+        match helper::file_sha256(p) {
+            Ok(sha256) if sha256 == known.sha256 => {
+                known.mtime_secs = mtime_secs;
+                known.mtime_nanos = mtime_nanos;
+                self.dirty = true;
+                None
+            }
+            _ => Some("changed"),
+        }
+    }
+
+    // everything recorded under the folder or file @p
+    pub fn under<'a>(&'a self, p: &'a Path) -> impl Iterator<Item = &'a FileState> + 'a {
+        self.files.iter().filter(move |(f, _)| f.starts_with(p)).map(|(_, state)| state)
+    }
+
+    // forget files that don't exist anymore
+    pub fn prune(&mut self) {
+        let before = self.files.len();
+        self.files.retain(|p, _| p.exists());
+        if self.files.len() != before {
+            self.dirty = true;
+        }
+    }
+}
@@ -0,0 +1,178 @@
+// what every tracker is doing. the report is json so scripts can read it, lines() turns it into text for people. a
+// running memury card answers from memory, otherwise it's put together from the files it left behind
+use crate::service::conflicts;
+use crate::service::control;
+use crate::service::destination::{self, Target};
+use crate::service::journal::{Journal, JOURNAL_FILE};
+use crate::service::pause::{PauseMode, PauseState};
+use crate::service::service::sync_dir;
+use crate::service::state::StateIndex;
+use crate::service::tracker::{get_json_settings_descriptors, tracker_dir, SaveDef, SaveOpts};
+use argh::FromArgs;
+use chrono::TimeZone;
+use serde_json::{json, Value};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use walkdir::WalkDir;
+
+// source files the tracker would copy
+fn count_files(save: &SaveDef) -> usize {
+    WalkDir::new(&save.path).follow_links(true).into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.path().is_file())
+        .filter(|e| conflicts::cloud_conflict_original(e.path()).is_none())
+        .filter(|e| match &save.options {
+            SaveOpts::Dir(d) => d.meets_rules(e.path()),
+            SaveOpts::File(_) => true,
+        })
+        .count()
+}
+
+fn tracker_report(save: &SaveDef, files: usize, paused: &PauseState, journal: &Journal, index: &StateIndex,
+    targets: &[Target]) -> Value {
+    let (kind, rules) = match &save.options {
+        SaveOpts::Dir(d) => ("dir", d.rules_lines()),
+        SaveOpts::File(_) => ("file", vec![]),
+    };
+    let synced: Vec<i64> = index.under(&save.path).map(|state| state.copied).collect();
+    let last_copy = synced.iter().max().filter(|t| **t > 0)
+        .map(|t| chrono::Local.timestamp_opt(*t, 0).unwrap().to_rfc3339());
+    let jobs: Vec<_> = journal.jobs().iter().filter(|(src, _)| src.starts_with(&save.path)).collect();
+    let errors: Vec<Value> = jobs.iter()
+        .filter_map(|(src, job)| {
+            job.error.as_ref().map(|e| json!({ "file": src, "attempts": job.attempts, "error": e }))
+        })
+        .collect();
+    let destinations: Vec<&str> = targets.iter()
+        .filter(|t| save.sends_to(&t.name))
+        .map(|t| t.name.as_str())
+        .collect();
+    json!({
+        "name": save.name,
+        "path": save.path,
+        "exists": save.path.exists(),
+        "kind": kind,
+        "mode": if paused.is_paused(&save.name) { "paused" } else { "watching" },
+        "sync_folder": save.sync_loc,
+        "destinations": destinations,
+        "rules": rules,
+        "files": files,
+        "synced": synced.len(),
+        "last_copy": last_copy,
+        "queued": paused.queued_count(&save.name),
+        "pending": jobs.len(),
+        "errors": errors,
+    })
+}
+
+// @pid is the running memury card's, None when the report is read from disk
+pub fn report(pid: Option<u32>, library: &str, saves: &[&SaveDef], paused: &PauseState, journal: &Mutex<Journal>,
+    index: &StateIndex, targets: &[Target]) -> Value {
+    let mut saves = saves.to_vec();
+    saves.sort_by(|a, b| a.name.cmp(&b.name));
+    // walking the save folders takes a while, the scanner thread needs the journal in the meantime
+    let files: Vec<usize> = saves.iter().map(|save| count_files(save)).collect();
+    let journal = journal.lock().unwrap();
+    let trackers: Vec<Value> = saves.iter().zip(files)
+        .map(|(save, files)| tracker_report(save, files, paused, &journal, index, targets))
+        .collect();
+    let destinations: Vec<Value> = targets.iter().map(|t| t.report()).collect();
+    json!({
+        "running": pid.is_some(),
+        "pid": pid,
+        "library": library,
+        "all_paused": paused.all_paused(),
+        "destinations": destinations,
+        "trackers": trackers,
+    })
+}
+
+fn yes_no(v: &Value) -> &'static str {
+    if v.as_bool().unwrap_or(false) { "yes" } else { "no" }
+}
+
+// the report as text
+pub fn lines(report: &Value) -> Vec<String> {
+    let mut lines = vec![];
+    match report["pid"].as_u64() {
+        Some(pid) if report["running"].as_bool().unwrap_or(false) => {
+            lines.push(format!("memurycard is running (pid {})", pid));
+        }
+        _ => lines.push("memurycard isn't running".to_string()),
+    }
+    lines.push(format!("library: {}", report["library"].as_str().unwrap_or("")));
+    if report["all_paused"].as_bool().unwrap_or(false) {
+        lines.push("all trackers are paused".to_string());
+    }
+    for d in report["destinations"].as_array().into_iter().flatten() {
+        let reachable = match d["reachable"].as_bool() {
+            Some(true) => "reachable",
+            Some(false) => "unreachable",
+            None => "not tried yet",
+        };
+        lines.push(format!("destination {} {} [{}] pending: {}", d["name"].as_str().unwrap_or(""), d["path"],
+            reachable, d["pending"]));
+        if let Some(secs) = d["retry_in"].as_u64() {
+            lines.push(format!("  trying again in {}s", secs));
+        }
+        for e in d["errors"].as_array().into_iter().flatten() {
+            let error = e["error"].as_str().unwrap_or("");
+            lines.push(format!("  failed {} times: {} {}", e["attempts"], e["file"], error));
+        }
+    }
+    for t in report["trackers"].as_array().into_iter().flatten() {
+        lines.push(format!("{} [{}] {} -> {}", t["name"].as_str().unwrap_or(""), t["mode"].as_str().unwrap_or(""),
+            t["path"], t["sync_folder"]));
+        let destinations: Vec<&str> = t["destinations"].as_array().into_iter().flatten()
+            .filter_map(|d| d.as_str())
+            .collect();
+        if !destinations.is_empty() {
+            lines.push(format!("  also copied to: {}", destinations.join(", ")));
+        }
+        let last_copy = t["last_copy"].as_str()
+            .and_then(|s| chrono::DateTime::parse_from_rfc3339(s).ok())
+            .map(|t| t.format("%Y-%m-%d %H:%M:%S").to_string())
+            .unwrap_or_else(|| "never".to_string());
+        lines.push(format!("  {} exists: {} files: {} synced: {} last copy: {}", t["kind"].as_str().unwrap_or(""),
+            yes_no(&t["exists"]), t["files"], t["synced"], last_copy));
+        for rule in t["rules"].as_array().into_iter().flatten() {
+            lines.push(format!("  {}", rule.as_str().unwrap_or("")));
+        }
+        lines.push(format!("  queued: {} pending: {}", t["queued"], t["pending"]));
+        for e in t["errors"].as_array().into_iter().flatten() {
+            let error = e["error"].as_str().unwrap_or("");
+            lines.push(format!("  failed {} times: {} {}", e["attempts"], e["file"], error));
+        }
+    }
+    lines
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "status")]
+/// show every tracker, its rules, file counts, last copy and errors
+pub struct StatusArgs {
+    /// print the report as json
+    #[argh(switch)]
+    json: bool,
+}
+
+pub fn command(settings: &Value, args: StatusArgs) -> Result<(), String> {
+    let report = if control::is_running() {
+        control::call("status", json!({}))?
+    } else {
+        let saves = get_json_settings_descriptors(&tracker_dir(settings));
+        let saves: Vec<&SaveDef> = saves.iter().collect();
+        let paused = PauseState::load(PauseMode::from_settings(settings));
+        let targets = destination::read_targets(settings);
+        let journal = Mutex::new(Journal::read(PathBuf::from(JOURNAL_FILE)));
+        report(None, &sync_dir(settings), &saves, &paused, &journal, &StateIndex::load(), &targets)
+    };
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&report).unwrap());
+    } else {
+        for line in lines(&report) {
+            println!("{}", line);
+        }
+    }
+    Ok(())
+}
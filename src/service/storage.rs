@@ -0,0 +1,378 @@
+// somewhere library files can be kept. files are named by keys, their path inside the library with / separators, so
+// every kind of storage sees the same names whatever the os. the watcher only goes through this trait to reach
+// destinations, a new kind of destination is a new Storage
+use crate::helper;
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Result};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use walkdir::WalkDir;
+
+// storages on a web server give up on one that doesn't answer after CONNECT_TIMEOUT and on a transfer after TIMEOUT
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(15);
+const TIMEOUT: Duration = Duration::from_secs(120);
+
+pub struct Stat {
+    pub size: u64,
+    // unix seconds
+    pub modified: i64,
+    // None if the storage can't tell without downloading the file
+    pub sha256: Option<String>,
+}
+
+// an older or current version of a file
+pub struct Revision {
+    pub id: String,
+    pub time: i64,
+    pub size: u64,
+}
+
+// id of the only revision of storages that don't keep old versions
+pub static CURRENT: &str = "current";
+
+pub trait Storage: Send {
+    // where the storage is, for logs and the status report
+    fn location(&self) -> String;
+
+    // can the storage be written to right now, as far as can be told without connecting to it. for a server there's
+    // no telling, one that's down fails the copy and it's tried again later
+    fn reachable(&self) -> bool {
+        true
+    }
+
+    // store the file @src as @key, replacing what's there without anyone seeing half a file. returns bytes written
+    fn put(&mut self, key: &str, src: &Path) -> Result<u64>;
+
+    // copy @key out of the storage to @dst
+    fn get(&mut self, key: &str, dst: &Path) -> Result<u64>;
+
+    // None if there's nothing stored as @key
+    fn stat(&mut self, key: &str) -> Result<Option<Stat>>;
+
+    // does stat give the sha256 of files, without it there's no point asking before a copy
+    fn hashes(&self) -> bool {
+        true
+    }
+
+    // every key that starts with @prefix
+    fn list(&mut self, prefix: &str) -> Result<Vec<String>>;
+
+    fn delete(&mut self, key: &str) -> Result<()>;
+
+    // versions of @key, oldest first. storages that don't keep old versions only have the current one
+    fn revisions(&mut self, key: &str) -> Result<Vec<Revision>> {
+        Ok(self.stat(key)?
+            .map(|stat| Revision { id: CURRENT.to_string(), time: stat.modified, size: stat.size })
+            .into_iter()
+            .collect())
+    }
+
+    // copy version @id of @key out of the storage to @dst
+    fn get_revision(&mut self, key: &str, id: &str, dst: &Path) -> Result<u64> {
+        if id != CURRENT {
+            return Err(Error::new(ErrorKind::NotFound, format!("no revision {} of {}", id, key)));
+        }
+        self.get(key, dst)
+    }
+
+    // @key written out in full, ie for logs and events
+    fn describe(&self, key: &str) -> String {
+        format!("{}/{}", self.location().trim_end_matches('/'), key)
+    }
+}
+
+// turn a path relative to the library into a key
+pub fn key_for(rel: &Path) -> String {
+    let parts: Vec<String> = rel.iter().map(|p| p.to_string_lossy().to_string()).collect();
+    parts.join("/")
+}
+
+// for storages on a web server
+pub fn http_agent() -> ureq::Agent {
+    ureq::AgentBuilder::new().timeout_connect(CONNECT_TIMEOUT).timeout(TIMEOUT).build()
+}
+
+// a failed request as an io error. a server that can't be connected to is NotConnected, which leaves its destination
+// alone for a while
+pub fn http_error(e: ureq::Error) -> Error {
+    match e {
+        ureq::Error::Status(404, _) => Error::new(ErrorKind::NotFound, "not found on the server"),
+        ureq::Error::Status(code, response) => {
+            Error::other(format!("server said {} {}", code, response.status_text()))
+        }
+        ureq::Error::Transport(t) => Error::new(ErrorKind::NotConnected, t.to_string()),
+    }
+}
+
+// write the body of @response to @dst, under a temporary name until it's all there
+pub fn download(response: ureq::Response, dst: &Path) -> Result<u64> {
+    if let Some(parent) = dst.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let tmp = helper::temp_path(dst);
+    let written = std::fs::File::create(&tmp)
+        .and_then(|mut file| std::io::copy(&mut response.into_reader(), &mut file))
+        .and_then(|bytes| std::fs::rename(&tmp, dst).map(|_| bytes));
+    if written.is_err() {
+        let _err = std::fs::remove_file(&tmp);
+    }
+    written
+}
+
+// a folder, the way the library has always been kept
+pub struct LocalStorage {
+    root: PathBuf,
+    // sha256 of files already hashed, with the size and modified time they had then. stat is asked before every copy
+    // and conflict check, a file that hasn't changed isn't read again
+    hashes: HashMap<PathBuf, (u64, SystemTime, String)>,
+}
+
+impl LocalStorage {
+    pub fn new(root: PathBuf) -> LocalStorage {
+        LocalStorage { root, hashes: HashMap::new() }
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        let mut p = self.root.clone();
+        p.extend(key.split('/').filter(|part| !part.is_empty()));
+        p
+    }
+}
+
+impl Storage for LocalStorage {
+    fn location(&self) -> String {
+        self.root.to_string_lossy().to_string()
+    }
+
+    // the folder has to be there already, a missing one is a drive that isn't plugged in or a share that isn't
+    // mounted and creating it would put the saves on the wrong disk
+    fn reachable(&self) -> bool {
+        self.root.is_dir()
+    }
+
+    fn put(&mut self, key: &str, src: &Path) -> Result<u64> {
+        let dst = self.path(key);
+        self.hashes.remove(&dst);
+        std::fs::create_dir_all(dst.parent().unwrap())?;
+        helper::copy_atomic(src, &dst)
+    }
+
+    fn get(&mut self, key: &str, dst: &Path) -> Result<u64> {
+        if let Some(parent) = dst.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        helper::copy_atomic(&self.path(key), dst)
+    }
+
+    fn stat(&mut self, key: &str) -> Result<Option<Stat>> {
+        let p = self.path(key);
+        let meta = match std::fs::metadata(&p) {
+            Ok(meta) if meta.is_file() => meta,
+            Ok(_) => return Ok(None),
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let (size, modified) = (meta.len(), meta.modified()?);
+        let sha256 = match self.hashes.get(&p) {
+            Some((s, m, sha256)) if *s == size && *m == modified => sha256.clone(),
+            _ => {
+                let sha256 = helper::file_sha256(&p)?;
+                self.hashes.insert(p, (size, modified, sha256.clone()));
+                sha256
+            }
+        };
+        let modified = modified.duration_since(std::time::UNIX_EPOCH).map_or(0, |d| d.as_secs() as i64);
+        Ok(Some(Stat { size, modified, sha256: Some(sha256) }))
+    }
+
+    fn list(&mut self, prefix: &str) -> Result<Vec<String>> {
+        let mut keys = vec![];
+        for entry in WalkDir::new(&self.root).into_iter().filter_map(|e| e.ok()) {
+            if !entry.file_type().is_file() || entry.file_name().to_string_lossy().ends_with(".memurycard-tmp") {
+                continue;
+            }
+            let key = key_for(entry.path().strip_prefix(&self.root).unwrap());
+            if key.starts_with(prefix) {
+                keys.push(key);
+            }
+        }
+        keys.sort();
+        Ok(keys)
+    }
+
+    fn delete(&mut self, key: &str) -> Result<()> {
+        let p = self.path(key);
+        self.hashes.remove(&p);
+        std::fs::remove_file(p)
+    }
+}
+
+// keeps everything in memory, every version of every file. for trying out the copy path without touching a disk
+#[cfg(test)]
+#[derive(Default)]
+pub struct MemoryStorage {
+    files: HashMap<String, Vec<(Revision, Vec<u8>)>>,
+    // pretend the storage went away, to see failures handled
+    pub offline: bool,
+}
+
+#[cfg(test)]
+impl MemoryStorage {
+    pub fn new() -> MemoryStorage {
+        MemoryStorage::default()
+    }
+
+    fn latest(&self, key: &str) -> Result<&(Revision, Vec<u8>)> {
+        self.files.get(key).and_then(|versions| versions.last())
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("{} isn't stored", key)))
+    }
+
+    fn check_online(&self) -> Result<()> {
+        if self.offline {
+            return Err(Error::new(ErrorKind::NotConnected, "memory storage is offline"));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+fn write_out(bytes: &[u8], dst: &Path) -> Result<u64> {
+    if let Some(parent) = dst.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(dst, bytes)?;
+    Ok(bytes.len() as u64)
+}
+
+#[cfg(test)]
+impl Storage for MemoryStorage {
+    fn location(&self) -> String {
+        "memory:".to_string()
+    }
+
+    fn reachable(&self) -> bool {
+        !self.offline
+    }
+
+    fn put(&mut self, key: &str, src: &Path) -> Result<u64> {
+        self.check_online()?;
+        let bytes = std::fs::read(src)?;
+        let versions = self.files.entry(key.to_string()).or_default();
+        let revision = Revision {
+            id: versions.len().to_string(),
+            time: chrono::Utc::now().timestamp(),
+            size: bytes.len() as u64,
+        };
+        versions.push((revision, bytes));
+        Ok(versions.last().unwrap().0.size)
+    }
+
+    fn get(&mut self, key: &str, dst: &Path) -> Result<u64> {
+        self.check_online()?;
+        write_out(&self.latest(key)?.1, dst)
+    }
+
+    fn stat(&mut self, key: &str) -> Result<Option<Stat>> {
+        self.check_online()?;
+        Ok(self.latest(key).ok().map(|(revision, bytes)| Stat {
+            size: revision.size,
+            modified: revision.time,
+            sha256: Some(helper::sha256_hex(bytes)),
+        }))
+    }
+
+    fn list(&mut self, prefix: &str) -> Result<Vec<String>> {
+        self.check_online()?;
+        let mut keys: Vec<String> = self.files.keys().filter(|k| k.starts_with(prefix)).cloned().collect();
+        keys.sort();
+        Ok(keys)
+    }
+
+    fn delete(&mut self, key: &str) -> Result<()> {
+        self.check_online()?;
+        self.files.remove(key)
+            .map(|_| ())
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("{} isn't stored", key)))
+    }
+
+    fn revisions(&mut self, key: &str) -> Result<Vec<Revision>> {
+        self.check_online()?;
+        Ok(self.files.get(key).into_iter().flatten()
+            .map(|(r, _)| Revision { id: r.id.clone(), time: r.time, size: r.size })
+            .collect())
+    }
+
+    fn get_revision(&mut self, key: &str, id: &str, dst: &Path) -> Result<u64> {
+        self.check_online()?;
+        let (_, bytes) = self.files.get(key).into_iter().flatten()
+            .find(|(r, _)| r.id == id)
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("no revision {} of {}", id, key)))?;
+        write_out(bytes, dst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("memurycard-storage-{}-{}", name, std::process::id()));
+        let _err = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    // puts two versions of a/b.sav and one of c.sav into @storage and checks what it says about them
+    fn check(storage: &mut dyn Storage, dir: &Path, keeps_versions: bool) {
+        let src = dir.join("src.sav");
+        std::fs::write(&src, b"one").unwrap();
+        assert!(storage.stat("a/b.sav").unwrap().is_none());
+        assert_eq!(storage.put("a/b.sav", &src).unwrap(), 3);
+        std::fs::write(&src, b"second").unwrap();
+        assert_eq!(storage.put("a/b.sav", &src).unwrap(), 6);
+        storage.put("c.sav", &src).unwrap();
+
+        let stat = storage.stat("a/b.sav").unwrap().unwrap();
+        assert_eq!(stat.size, 6);
+        assert_eq!(stat.sha256, Some(helper::sha256_hex(b"second")));
+        assert_eq!(storage.list("").unwrap(), ["a/b.sav", "c.sav"]);
+        assert_eq!(storage.list("a/").unwrap(), ["a/b.sav"]);
+
+        let revisions = storage.revisions("a/b.sav").unwrap();
+        assert_eq!(revisions.len(), if keeps_versions { 2 } else { 1 });
+        let dst = dir.join("out/first.sav");
+        storage.get_revision("a/b.sav", &revisions[0].id, &dst).unwrap();
+        let first: &[u8] = if keeps_versions { b"one" } else { b"second" };
+        assert_eq!(std::fs::read(&dst).unwrap(), first);
+
+        storage.delete("c.sav").unwrap();
+        assert!(storage.stat("c.sav").unwrap().is_none());
+    }
+
+    #[test]
+    fn memory_storage() {
+        let dir = temp_dir("memory");
+        let mut storage = MemoryStorage::new();
+        check(&mut storage, &dir, true);
+        storage.offline = true;
+        assert!(!storage.reachable());
+        assert_eq!(storage.stat("a/b.sav").err().map(|e| e.kind()), Some(ErrorKind::NotConnected));
+        let _err = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn local_storage() {
+        let dir = temp_dir("local");
+        let mut storage = LocalStorage::new(dir.join("library"));
+        assert!(!storage.reachable());
+        std::fs::create_dir_all(dir.join("library")).unwrap();
+        assert!(storage.reachable());
+        check(&mut storage, &dir, false);
+
+        // a file changed behind the storage's back isn't given its old hash
+        std::fs::write(dir.join("library/a/b.sav"), b"changed!").unwrap();
+        let stat = storage.stat("a/b.sav").unwrap().unwrap();
+        assert_eq!(stat.sha256, Some(helper::sha256_hex(b"changed!")));
+        let _err = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -8,9 +8,10 @@ pub fn uninstall() {
     crate::linux::helper::uninstall();
 }
 
+// returns true if this process is now the background process and should keep running
 #[cfg(target_os = "linux")]
-pub fn send_to_background() {
-    crate::linux::helper::send_to_background();
+pub fn send_to_background(log: &std::path::Path) -> bool {
+    crate::linux::helper::send_to_background(log)
 }
 
 #[cfg(target_os = "windows")]
@@ -24,10 +25,27 @@ pub fn uninstall() {
 }
 
 #[cfg(target_os = "windows")]
-pub fn send_to_background() {
+pub fn send_to_background(_log: &std::path::Path) -> bool {
     crate::windows::helper::send_to_background();
+    false
 }
 
-pub fn enable() { }
+#[cfg(target_os = "linux")]
+pub fn enable() {
+    crate::linux::helper::enable();
+}
+
+#[cfg(target_os = "linux")]
+pub fn disable() {
+    crate::linux::helper::disable();
+}
 
-pub fn disable() { }
+#[cfg(target_os = "windows")]
+pub fn enable() {
+    log::info!("enabling without installing isn't supported on windows, run with -i instead");
+}
+
+#[cfg(target_os = "windows")]
+pub fn disable() {
+    log::info!("disabling without uninstalling isn't supported on windows, run with -u instead");
+}
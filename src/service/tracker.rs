@@ -0,0 +1,448 @@
+use crate::helper;
+use crate::helper::sanitize_slashes;
+use crate::service::conflicts::Strategy;
+use crate::service::control;
+use argh::FromArgs;
+use serde_json::{json, Value};
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+pub struct SaveFile {
+}
+
+pub enum RuleList {
+    Allowed(Vec<String>),
+    Disallowed(Vec<String>),
+}
+
+pub struct SaveDir {
+    pub rule_list: RuleList,
+}
+
+pub enum SaveOpts {
+    File(SaveFile),
+    Dir(SaveDir),
+}
+
+pub struct SaveDef {
+    pub name: String,
+    pub path: PathBuf,
+    pub sync_loc: PathBuf,
+    pub options: SaveOpts,
+    // also copy newer library versions from other computers back over the local saves
+    pub two_way: bool,
+    pub conflicts: Strategy,
+    // names of the destinations to copy to besides the library, None for all of them
+    pub destinations: Option<Vec<String>>,
+}
+
+impl SaveDef {
+    pub fn print(&self) {
+        log::info!("{:?}", self.path);
+    }
+
+    pub fn sends_to(&self, destination: &str) -> bool {
+        self.destinations.as_ref().is_none_or(|names| names.iter().any(|n| n == destination))
+    }
+}
+
+impl SaveDir {
+    #[allow(dead_code, clippy::needless_range_loop)]
+    pub fn print(&self) {
+        let rule_list = match &self.rule_list {
+            RuleList::Allowed(v) =>  {
+                log::info!("allowed_filetypes");
+                v
+            }
+            RuleList::Disallowed(v) => {
+                log::info!("disallowed_filetypes");
+                v
+            }
+        };
+        for i in 0 .. rule_list.len() {
+            log::info!("{}", rule_list[i]);
+        }
+    }
+
+    pub fn print_rules(&self) {
+        for line in self.rules_lines() {
+            log::info!("{}", line);
+        }
+    }
+
+    pub fn rules_lines(&self) -> Vec<String> {
+        let mut lines = vec![];
+        match &self.rule_list {
+            RuleList::Allowed(v) =>  {
+                lines.push("Allowed:".to_string());
+                for ftype in v {
+                    lines.push(format!("\t{}", ftype));
+                }
+            }
+            RuleList::Disallowed(v) => {
+                lines.push("Disallowed:".to_string());
+                for disallowed in v {
+                    lines.push(format!("\t{}", disallowed));
+                }
+            }
+        }
+        lines
+    }
+
+    pub fn meets_rules(&self, p: &Path) -> bool {
+        match &self.rule_list {
+            RuleList::Allowed(v) =>  {
+                let ext = match p.extension() {
+                    Some(ext) => ext.to_str().unwrap(),
+                    None => return false,
+                };
+                for ftype in v {
+                    if ftype == ext {
+                        return true;
+                    }
+                }
+                false
+            }
+            RuleList::Disallowed(v) => {
+                let pstr = p.to_str().unwrap();
+                for disallowed in v {
+                    if pstr.ends_with(disallowed) {
+                        return false;
+                    }
+                }
+                true
+            }
+        }
+    }
+}
+
+// parse user generated json files indicating location of content storage areas
+#[allow(clippy::needless_range_loop, clippy::len_zero, clippy::redundant_field_names)]
+pub fn parse_save_json(json_file: &str, save_accu: &mut Vec<SaveDef>) {
+    let bytes = std::fs::read_to_string(json_file).unwrap();
+    let json: Value = serde_json::from_str(&bytes).unwrap();
+    let saves = json["saves"].as_array().unwrap();
+
+    for i in 0 .. saves.len() {
+        // json elements with the "saves_path" field populated are directories
+        let mut path = PathBuf::new();
+        let name =  if saves[i]["name"] == Value::Null { "NO_NAME".to_string() }
+                    else { crate::helper::strip_quotes(saves[i]["name"].as_str().unwrap()) };
+        let sync_loc =  if saves[i]["sync_folder"] == Value::Null { PathBuf::from("") }
+                        else { PathBuf::from(crate::helper::strip_quotes(saves[i]["sync_folder"].as_str().unwrap())) };
+
+        let saveopt = if saves[i]["saves_path"] != Value::Null {
+            let dir = sanitize_slashes(&crate::helper::strip_quotes(saves[i]["saves_path"].as_str().unwrap()));
+            path.push(dir);
+            log::debug!("{:?}", path);
+            if saves[i]["allowed_filetypes"] != Value::Null && saves[i]["disallowed_filetypes"] != Value::Null {
+                log::error!("{:?} can only have an allow list or disallow list", path);
+                continue;
+            }
+
+            let rule_list = if saves[i]["allowed_filetypes"] == Value::Null && saves[i]["disallowed_filetypes"] == Value::Null {
+                log::debug!("providing empty disallow list for {:?}", path);
+                let empty_disallowed_vec: Vec<String> = vec![];
+                RuleList::Disallowed(empty_disallowed_vec)
+            } else if saves[i]["allowed_filetypes"] != Value::Null {
+                let allowed = saves[i]["allowed_filetypes"].as_array().unwrap();
+                let mut allowed_vec: Vec<String> = vec![];
+                for j in 0 .. allowed.len() {
+                    let filetypes_str = allowed[j].as_str().unwrap().to_string();
+                    if filetypes_str.len() > 0 {
+                        allowed_vec.push(filetypes_str);
+                    }
+                }
+                RuleList::Allowed(allowed_vec)
+            } else /* if saves[i]["disallowed_filetypes"] != Value::Null */ {
+                let disallowed = saves[i]["disallowed_filetypes"].as_array().unwrap();
+                let mut disallowed_vec: Vec<String> = vec![];
+                for j in 0..disallowed.len() {
+                    let disallowed_str = disallowed[j].as_str().unwrap().to_string();
+                    if disallowed_str.len() > 0 {
+                        disallowed_vec.push(disallowed_str);
+                    }
+                }
+                RuleList::Disallowed(disallowed_vec)
+            };
+            let savedir = SaveDir {
+                rule_list: rule_list,
+            };
+            SaveOpts::Dir(savedir)
+        } else {
+            path.push(helper::strip_quotes(saves[i]["file"].as_str().unwrap()));
+            let savefile = SaveFile {
+            };
+            SaveOpts::File(savefile)
+        };
+        let savedef = SaveDef {
+            name: name,
+            path: path,
+            sync_loc: sync_loc,
+            options: saveopt,
+            two_way: saves[i]["two_way"].as_bool().unwrap_or(false),
+            conflicts: Strategy::from_tracker(&saves[i]),
+            destinations: saves[i]["destinations"].as_array()
+                .map(|names| names.iter().filter_map(|n| n.as_str()).map(|n| n.to_string()).collect()),
+        };
+        save_accu.push(savedef);
+    }
+}
+
+// find all files in @json_dir that end in .json
+fn find_tracker_files(json_dir: &str) -> Vec<PathBuf> {
+    let json_dir = helper::strip_quotes(json_dir);
+    let mut files: Vec<PathBuf> = vec![];
+
+    for entry in WalkDir::new(json_dir)
+        .follow_links(true)
+        .into_iter()
+        .filter_map(|e| e.ok())
+    {
+        let f_name = entry.file_name().to_string_lossy();
+
+        if f_name.ends_with(".json") {
+            files.push(entry.path().to_path_buf());
+        }
+    }
+    files
+}
+
+// find all files in @json_dir that end in .json, return a vector of SaveDef's from them
+pub fn get_json_settings_descriptors(json_dir: &str) -> Vec<SaveDef> {
+    let mut save_accu: Vec<SaveDef> = vec![];
+    for entry in find_tracker_files(json_dir) {
+        parse_save_json(entry.to_str().unwrap(), &mut save_accu);
+    }
+    save_accu
+}
+
+// tracker dir is relative to the program directory unless the settings file says otherwise
+pub fn tracker_dir(settings: &Value) -> String {
+    let default = "trackers";
+    match settings["tracker_dir"].as_str() {
+        // older versions always read trackers/ whatever settings.json said, and the linux example settings named a
+        // folder that isn't there. keep finding the trackers of those installs
+        Some(dir) if !Path::new(&sanitize_slashes(dir)).is_dir() && Path::new(default).is_dir() => {
+            log::warn!("tracker_dir {:?} doesn't exist, reading trackers from {:?} instead", dir, default);
+            default.to_string()
+        }
+        Some(dir) => sanitize_slashes(dir),
+        None => default.to_string(),
+    }
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "tracker")]
+/// create, list and delete save trackers
+pub struct TrackerArgs {
+    #[argh(subcommand)]
+    cmd: TrackerCmd,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand)]
+enum TrackerCmd {
+    Add(TrackerAddArgs),
+    Remove(TrackerRemoveArgs),
+    List(TrackerListArgs),
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "add")]
+/// add a save location to a tracker file
+struct TrackerAddArgs {
+    /// unique name for the tracker
+    #[argh(option)]
+    name: String,
+
+    /// full path of the save folder or save file to track
+    #[argh(option)]
+    path: String,
+
+    /// folder created in the sync location to place saves in
+    #[argh(option, default = "String::new()")]
+    sync_folder: String,
+
+    /// filetype to copy, can be given multiple times. conflicts with --deny
+    #[argh(option)]
+    allow: Vec<String>,
+
+    /// filetype to ignore, can be given multiple times. conflicts with --allow
+    #[argh(option)]
+    deny: Vec<String>,
+
+    /// track a single file instead of a folder
+    #[argh(switch)]
+    file: bool,
+
+    /// copy newer saves from the library back to this computer too
+    #[argh(switch)]
+    two_way: bool,
+
+    /// destination to copy to besides the library, can be given multiple times. all of them if left out
+    #[argh(option)]
+    destination: Vec<String>,
+
+    /// tracker file to add the entry to, created if it doesn't exist
+    #[argh(option, default = "String::from(\"tracker.json\")")]
+    tracker_file: String,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "remove")]
+/// remove a save location from the tracker files
+struct TrackerRemoveArgs {
+    /// name of the tracker to remove
+    #[argh(positional)]
+    name: String,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "list")]
+/// list all save locations in the tracker files
+struct TrackerListArgs {
+}
+
+pub fn command(settings: &Value, args: TrackerArgs) -> Result<(), String> {
+    let json_dir = tracker_dir(settings);
+    match args.cmd {
+        TrackerCmd::Add(a) => add(&json_dir, a)?,
+        TrackerCmd::Remove(a) => remove(&json_dir, &a.name)?,
+        TrackerCmd::List(_) => {
+            list(&json_dir);
+            return Ok(());
+        }
+    }
+
+    // let a running memury card pick up the change without restarting it
+    if control::is_running() {
+        control::call("reload", json!({}))?;
+        log::info!("reloaded running memurycard");
+    }
+    Ok(())
+}
+
+// two sync folders collide if they are the same folder or if one lives inside the other. an empty sync folder is the
+// root of the library so it only collides with another empty sync folder
+fn sync_folders_collide(a: &Path, b: &Path) -> bool {
+    let a = PathBuf::from(sanitize_slashes(a.to_str().unwrap()));
+    let b = PathBuf::from(sanitize_slashes(b.to_str().unwrap()));
+    let a_empty = a.as_os_str().is_empty() || a == Path::new(".");
+    let b_empty = b.as_os_str().is_empty() || b == Path::new(".");
+    if a_empty || b_empty {
+        return a_empty && b_empty;
+    }
+    a.starts_with(&b) || b.starts_with(&a)
+}
+
+fn add(json_dir: &str, args: TrackerAddArgs) -> Result<(), String> {
+    if !args.allow.is_empty() && !args.deny.is_empty() {
+        return Err("a tracker can only have an allow list or disallow list".to_string());
+    }
+    if args.file && (!args.allow.is_empty() || !args.deny.is_empty()) {
+        return Err("file trackers can't have an allow list or disallow list".to_string());
+    }
+
+    let path = PathBuf::from(sanitize_slashes(&args.path));
+    if !path.exists() {
+        return Err(format!("{:?} doesn't exist", path));
+    }
+    if args.file && !path.is_file() {
+        return Err(format!("{:?} is not a file", path));
+    }
+    if !args.file && !path.is_dir() {
+        return Err(format!("{:?} is not a folder, use --file to track a single file", path));
+    }
+
+    let sync_loc = PathBuf::from(&args.sync_folder);
+    for save in get_json_settings_descriptors(json_dir) {
+        if save.name == args.name {
+            return Err(format!("a tracker named {} already exists", args.name));
+        }
+        if sync_folders_collide(&save.sync_loc, &sync_loc) {
+            return Err(format!("sync folder {:?} collides with {:?} used by {}", sync_loc, save.sync_loc, save.name));
+        }
+    }
+
+    let mut entry = json!({ "name": args.name });
+    if args.file {
+        entry["file"] = json!(path);
+    } else {
+        entry["saves_path"] = json!(path);
+    }
+    entry["sync_folder"] = json!(args.sync_folder);
+    if !args.allow.is_empty() {
+        entry["allowed_filetypes"] = json!(args.allow);
+    }
+    if !args.deny.is_empty() {
+        entry["disallowed_filetypes"] = json!(args.deny);
+    }
+    if args.two_way {
+        entry["two_way"] = json!(true);
+    }
+    if !args.destination.is_empty() {
+        entry["destinations"] = json!(args.destination);
+    }
+
+    std::fs::create_dir_all(json_dir).map_err(|e| format!("could not create {}: {:?}", json_dir, e))?;
+    let mut tracker_file = PathBuf::from(json_dir);
+    tracker_file.push(&args.tracker_file);
+    let mut json = if tracker_file.exists() {
+        helper::parse_json(&tracker_file).map_err(|e| format!("could not parse {:?}: {:?}", tracker_file, e))?
+    } else {
+        json!({ "saves": [] })
+    };
+    match json["saves"].as_array_mut() {
+        Some(saves) => saves.push(entry),
+        None => return Err(format!("{:?} has no saves list", tracker_file)),
+    }
+    helper::write_json(&tracker_file, &json).map_err(|e| format!("could not write {:?}: {:?}", tracker_file, e))?;
+    log::info!("added {} to {:?}", args.name, tracker_file);
+    Ok(())
+}
+
+fn remove(json_dir: &str, name: &str) -> Result<(), String> {
+    let mut removed = false;
+    for tracker_file in find_tracker_files(json_dir) {
+        let mut json = helper::parse_json(&tracker_file).map_err(|e| format!("could not parse {:?}: {:?}", tracker_file, e))?;
+        let saves = match json["saves"].as_array_mut() {
+            Some(saves) => saves,
+            None => continue,
+        };
+        let before = saves.len();
+        saves.retain(|s| s["name"].as_str() != Some(name));
+        if saves.len() != before {
+            helper::write_json(&tracker_file, &json).map_err(|e| format!("could not write {:?}: {:?}", tracker_file, e))?;
+            log::info!("removed {} from {:?}", name, tracker_file);
+            removed = true;
+        }
+    }
+
+    if !removed {
+        return Err(format!("no tracker named {}", name));
+    }
+    Ok(())
+}
+
+fn list(json_dir: &str) {
+    for tracker_file in find_tracker_files(json_dir) {
+        let mut saves: Vec<SaveDef> = vec![];
+        parse_save_json(tracker_file.to_str().unwrap(), &mut saves);
+        log::info!("{:?}", tracker_file);
+        for save in saves {
+            let kind = match &save.options {
+                SaveOpts::File(_) => "file",
+                SaveOpts::Dir(_) => "dir",
+            };
+            let direction = if save.two_way { "<->" } else { "->" };
+            log::info!("  {} ({}) {:?} {} {:?}", save.name, kind, save.path, direction, save.sync_loc);
+            if let Some(names) = &save.destinations {
+                log::info!("  destinations: {}", names.join(", "));
+            }
+            if let SaveOpts::Dir(d) = &save.options {
+                d.print_rules();
+            }
+        }
+    }
+}
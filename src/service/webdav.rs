@@ -0,0 +1,407 @@
+// destinations on a webdav server, ie nextcloud or owncloud:
+// {"name": "cloud", "type": "webdav", "url": "https://cloud.example.com/remote.php/dav/files/alex/saves",
+//  "user": "alex", "password_env": "MEMURYCARD_CLOUD_PASSWORD"}
+// the password comes from the environment variable named by "password_env" or the first line of "password_file" so
+// it doesn't have to sit in settings.json. each file's sha256 is kept on the server as a webdav property so unchanged
+// saves aren't uploaded again. plain webdav has no versions, only the current file is listed as a revision even when
+// the server keeps older ones, ie nextcloud's versions app, and those have to be got back through the server itself
+use crate::helper;
+use crate::service::storage::{self, http_error, Stat, Storage};
+use base64::Engine;
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use quick_xml::events::Event;
+use serde_json::Value;
+use std::collections::HashSet;
+use std::io::{Error, ErrorKind, Result};
+use std::path::Path;
+
+// everything but unreserved characters is escaped in a path segment
+const SEGMENT: &AsciiSet = &NON_ALPHANUMERIC.remove(b'-').remove(b'_').remove(b'.').remove(b'~');
+
+// urn:memurycard is the namespace of the properties memury card keeps on the server
+static PROPFIND_BODY: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<d:propfind xmlns:d="DAV:" xmlns:m="urn:memurycard">
+  <d:prop><d:resourcetype/><d:getcontentlength/><d:getlastmodified/><m:sha256/></d:prop>
+</d:propfind>"#;
+
+pub struct WebdavStorage {
+    url: String,
+    // path part of the url, for turning the hrefs the server sends back into keys
+    base_path: String,
+    auth: Option<String>,
+    agent: ureq::Agent,
+    // collections already made this run
+    made: HashSet<String>,
+}
+
+// one <response> from a PROPFIND
+#[derive(Default)]
+struct Entry {
+    href: String,
+    collection: bool,
+    size: u64,
+    modified: i64,
+    sha256: Option<String>,
+}
+
+fn xml_error(e: quick_xml::Error) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("bad PROPFIND reply: {}", e))
+}
+
+// the responses in a 207 multistatus reply
+fn parse_multistatus(xml: &str) -> Result<Vec<Entry>> {
+    let mut reader = quick_xml::Reader::from_str(xml);
+    reader.config_mut().trim_text(true);
+    let mut entries = vec![];
+    let mut entry: Option<Entry> = None;
+    // local name of the element whose text comes next
+    let mut inside = Vec::new();
+    loop {
+        match reader.read_event().map_err(xml_error)? {
+            Event::Start(e) => {
+                let name = e.local_name().as_ref().to_vec();
+                match name.as_slice() {
+                    b"response" => entry = Some(Entry::default()),
+                    b"collection" => entry.iter_mut().for_each(|e| e.collection = true),
+                    _ => (),
+                }
+                inside = name;
+            }
+            Event::Empty(e) if e.local_name().as_ref() == b"collection" => {
+                entry.iter_mut().for_each(|e| e.collection = true);
+            }
+            Event::Text(t) => {
+                let text = t.unescape().map_err(xml_error)?.to_string();
+                if let Some(entry) = entry.as_mut() {
+                    match inside.as_slice() {
+                        b"href" => entry.href = text,
+                        b"getcontentlength" => entry.size = text.parse().unwrap_or(0),
+                        b"getlastmodified" => {
+                            entry.modified = chrono::DateTime::parse_from_rfc2822(&text).map_or(0, |t| t.timestamp());
+                        }
+                        b"sha256" if !text.is_empty() => entry.sha256 = Some(text),
+                        _ => (),
+                    }
+                }
+            }
+            Event::End(e) => {
+                if e.local_name().as_ref() == b"response" {
+                    entries.extend(entry.take());
+                }
+                inside.clear();
+            }
+            Event::Eof => break,
+            _ => (),
+        }
+    }
+    Ok(entries)
+}
+
+fn password(d: &Value) -> std::result::Result<Option<String>, String> {
+    if let Some(var) = d["password_env"].as_str() {
+        return std::env::var(var).map(Some).map_err(|_| format!("{} isn't set", var));
+    }
+    if let Some(file) = d["password_file"].as_str() {
+        let text = std::fs::read_to_string(file).map_err(|e| format!("could not read {}: {}", file, e))?;
+        return Ok(Some(text.lines().next().unwrap_or("").to_string()));
+    }
+    Ok(None)
+}
+
+impl WebdavStorage {
+    pub fn from_settings(d: &Value) -> std::result::Result<WebdavStorage, String> {
+        let url = match d["url"].as_str() {
+            Some(url) if url.starts_with("http://") || url.starts_with("https://") => url.trim_end_matches('/'),
+            _ => return Err("webdav destinations need an http:// or https:// url".to_string()),
+        };
+        let after_host = url.find("://").map_or(0, |i| i + 3);
+        let base_path = url[after_host..].find('/').map_or("", |i| &url[after_host + i..]);
+        let auth = match (d["user"].as_str(), password(d)?) {
+            (Some(user), password) => {
+                let pair = format!("{}:{}", user, password.unwrap_or_default());
+                Some(format!("Basic {}", base64::engine::general_purpose::STANDARD.encode(pair)))
+            }
+            (None, Some(_)) => return Err("webdav destinations with a password need a user".to_string()),
+            (None, None) => None,
+        };
+        Ok(WebdavStorage {
+            url: url.to_string(),
+            base_path: percent_decode_str(base_path).decode_utf8_lossy().to_string(),
+            auth,
+            agent: storage::http_agent(),
+            made: HashSet::new(),
+        })
+    }
+
+    fn url_for(&self, key: &str) -> String {
+        let mut url = self.url.clone();
+        for part in key.split('/').filter(|p| !p.is_empty()) {
+            url.push('/');
+            url.extend(utf8_percent_encode(part, SEGMENT));
+        }
+        url
+    }
+
+    fn request(&self, method: &str, url: &str) -> ureq::Request {
+        let request = self.agent.request(method, url);
+        match &self.auth {
+            Some(auth) => request.set("Authorization", auth),
+            None => request,
+        }
+    }
+
+    fn propfind(&self, url: &str, depth: &str) -> Result<Vec<Entry>> {
+        let response = self.request("PROPFIND", url)
+            .set("Depth", depth)
+            .set("Content-Type", "application/xml; charset=utf-8")
+            .send_string(PROPFIND_BODY)
+            .map_err(http_error)?;
+        parse_multistatus(&response.into_string()?)
+    }
+
+    // the key a href from the server points to, hrefs can be full urls or just the path and are escaped
+    fn key_for_href(&self, href: &str) -> String {
+        let path = match href.find("://") {
+            Some(i) => href[i + 3..].find('/').map_or("", |j| &href[i + 3 + j..]),
+            None => href,
+        };
+        let path = percent_decode_str(path).decode_utf8_lossy();
+        path.strip_prefix(&self.base_path).unwrap_or(&path).trim_matches('/').to_string()
+    }
+
+    // webdav can't make a collection inside one that isn't there, so each parent is made in turn
+    fn make_parents(&mut self, key: &str) -> Result<()> {
+        let parts: Vec<&str> = key.split('/').filter(|p| !p.is_empty()).collect();
+        let mut dir = String::new();
+        for part in parts.iter().take(parts.len().saturating_sub(1)) {
+            if !dir.is_empty() {
+                dir.push('/');
+            }
+            dir.push_str(part);
+            if self.made.contains(&dir) {
+                continue;
+            }
+            match self.request("MKCOL", &format!("{}/", self.url_for(&dir))).call() {
+                // 405 is the collection already being there
+                Ok(_) | Err(ureq::Error::Status(405, _)) => {
+                    self.made.insert(dir.clone());
+                }
+                Err(e) => return Err(http_error(e)),
+            }
+        }
+        Ok(())
+    }
+
+    fn set_sha256(&self, url: &str, sha256: &str) -> Result<()> {
+        let body = format!(r#"<?xml version="1.0" encoding="utf-8"?>
+<d:propertyupdate xmlns:d="DAV:" xmlns:m="urn:memurycard">
+  <d:set><d:prop><m:sha256>{}</m:sha256></d:prop></d:set>
+</d:propertyupdate>"#, sha256);
+        self.request("PROPPATCH", url)
+            .set("Content-Type", "application/xml; charset=utf-8")
+            .send_string(&body)
+            .map(|_| ())
+            .map_err(http_error)
+    }
+}
+
+impl Storage for WebdavStorage {
+    fn location(&self) -> String {
+        self.url.clone()
+    }
+
+    // uploaded under a temporary name then moved over the old file so nobody downloads half a save
+    fn put(&mut self, key: &str, src: &Path) -> Result<u64> {
+        let bytes = std::fs::read(src)?;
+        self.make_parents(key)?;
+        let dst = self.url_for(key);
+        let tmp = format!("{}.memurycard-tmp", dst);
+        self.request("PUT", &tmp).send_bytes(&bytes).map_err(http_error)?;
+        let moved = self.request("MOVE", &tmp)
+            .set("Destination", &dst)
+            .set("Overwrite", "T")
+            .call();
+        if let Err(e) = moved {
+            let _err = self.request("DELETE", &tmp).call();
+            return Err(http_error(e));
+        }
+        // without it the file is just uploaded again next time
+        if let Err(e) = self.set_sha256(&dst, &helper::sha256_hex(&bytes)) {
+            log::debug!("could not store the sha256 of {}: {:?}", dst, e);
+        }
+        Ok(bytes.len() as u64)
+    }
+
+    fn get(&mut self, key: &str, dst: &Path) -> Result<u64> {
+        let response = self.request("GET", &self.url_for(key)).call().map_err(http_error)?;
+        storage::download(response, dst)
+    }
+
+    fn stat(&mut self, key: &str) -> Result<Option<Stat>> {
+        let entries = match self.propfind(&self.url_for(key), "0") {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        Ok(entries.into_iter()
+            .find(|e| !e.collection)
+            .map(|e| Stat { size: e.size, modified: e.modified, sha256: e.sha256 }))
+    }
+
+    // servers often turn off Depth: infinity, so each collection is asked for in turn
+    fn list(&mut self, prefix: &str) -> Result<Vec<String>> {
+        let mut keys = vec![];
+        let mut dirs = vec![String::new()];
+        while let Some(dir) = dirs.pop() {
+            let url = format!("{}/", self.url_for(&dir));
+            for entry in self.propfind(&url, "1")? {
+                let key = self.key_for_href(&entry.href);
+                if key == dir || key.ends_with(".memurycard-tmp") {
+                    continue;
+                }
+                if entry.collection && (key.starts_with(prefix) || prefix.starts_with(&format!("{}/", key))) {
+                    dirs.push(key);
+                } else if !entry.collection && key.starts_with(prefix) {
+                    keys.push(key);
+                }
+            }
+        }
+        keys.sort();
+        Ok(keys)
+    }
+
+    fn delete(&mut self, key: &str) -> Result<()> {
+        self.request("DELETE", &self.url_for(key)).call().map(|_| ()).map_err(http_error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn storage(url: &str) -> WebdavStorage {
+        WebdavStorage::from_settings(&serde_json::json!({ "url": url })).unwrap()
+    }
+
+    // a Depth: 1 PROPFIND of a nextcloud folder. properties a file doesn't have come back in a 404 propstat
+    static NEXTCLOUD: &str = r#"<?xml version="1.0"?>
+<d:multistatus xmlns:d="DAV:" xmlns:s="http://sabredav.org/ns" xmlns:oc="http://owncloud.org/ns"
+    xmlns:nc="http://nextcloud.org/ns" xmlns:m="urn:memurycard">
+  <d:response>
+    <d:href>/remote.php/dav/files/alex/saves/</d:href>
+    <d:propstat>
+      <d:prop>
+        <d:resourcetype><d:collection/></d:resourcetype>
+        <d:getlastmodified>Sat, 19 Oct 2024 07:06:00 GMT</d:getlastmodified>
+      </d:prop>
+      <d:status>HTTP/1.1 200 OK</d:status>
+    </d:propstat>
+    <d:propstat>
+      <d:prop><d:getcontentlength/><m:sha256/></d:prop>
+      <d:status>HTTP/1.1 404 Not Found</d:status>
+    </d:propstat>
+  </d:response>
+  <d:response>
+    <d:href>/remote.php/dav/files/alex/saves/Pok%c3%a9mon%20Red%20%26%20Blue.sav</d:href>
+    <d:propstat>
+      <d:prop>
+        <d:resourcetype/>
+        <d:getcontentlength>32768</d:getcontentlength>
+        <d:getlastmodified>Sat, 19 Oct 2024 07:06:00 GMT</d:getlastmodified>
+        <m:sha256>9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08</m:sha256>
+      </d:prop>
+      <d:status>HTTP/1.1 200 OK</d:status>
+    </d:propstat>
+  </d:response>
+  <d:response>
+    <d:href>/remote.php/dav/files/alex/saves/snes/</d:href>
+    <d:propstat>
+      <d:prop><d:resourcetype><d:collection/></d:resourcetype></d:prop>
+      <d:status>HTTP/1.1 200 OK</d:status>
+    </d:propstat>
+  </d:response>
+  <d:response>
+    <d:href>/remote.php/dav/files/alex/saves/old.sav</d:href>
+    <d:propstat>
+      <d:prop>
+        <d:resourcetype/>
+        <d:getcontentlength>12</d:getcontentlength>
+        <d:getlastmodified>Tue, 02 Jan 2024 03:04:05 GMT</d:getlastmodified>
+      </d:prop>
+      <d:status>HTTP/1.1 200 OK</d:status>
+    </d:propstat>
+    <d:propstat>
+      <d:prop><m:sha256/></d:prop>
+      <d:status>HTTP/1.1 404 Not Found</d:status>
+    </d:propstat>
+  </d:response>
+</d:multistatus>"#;
+
+    // apache mod_dav with its own prefixes, and full urls for hrefs like some servers send
+    static APACHE: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<D:multistatus xmlns:D="DAV:" xmlns:ns0="urn:memurycard">
+<D:response xmlns:lp1="DAV:">
+<D:href>https://nas.local/dav/my%20saves/</D:href>
+<D:propstat>
+<D:prop>
+<lp1:resourcetype><D:collection></D:collection></lp1:resourcetype>
+</D:prop>
+<D:status>HTTP/1.1 200 OK</D:status>
+</D:propstat>
+</D:response>
+<D:response xmlns:lp1="DAV:">
+<D:href>https://nas.local/dav/my%20saves/gba/a%5b1%5d.sav</D:href>
+<D:propstat>
+<D:prop>
+<lp1:resourcetype/>
+<lp1:getcontentlength>131072</lp1:getcontentlength>
+<lp1:getlastmodified>Tue, 02 Jan 2024 03:04:05 GMT</lp1:getlastmodified>
+<ns0:sha256>a&#98;c</ns0:sha256>
+</D:prop>
+<D:status>HTTP/1.1 200 OK</D:status>
+</D:propstat>
+</D:response>
+</D:multistatus>"#;
+
+    #[test]
+    fn nextcloud_multistatus() {
+        let entries = parse_multistatus(NEXTCLOUD).unwrap();
+        assert_eq!(entries.len(), 4);
+        let storage = storage("https://cloud.example.com/remote.php/dav/files/alex/saves/");
+        let keys: Vec<String> = entries.iter().map(|e| storage.key_for_href(&e.href)).collect();
+        assert_eq!(keys, ["", "Pokémon Red & Blue.sav", "snes", "old.sav"]);
+
+        assert!(entries[0].collection);
+        assert_eq!(entries[0].sha256, None);
+        assert!(!entries[1].collection);
+        assert_eq!(entries[1].size, 32768);
+        assert_eq!(entries[1].modified, 1729321560);
+        assert_eq!(entries[1].sha256.as_deref(),
+            Some("9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08"));
+        assert!(entries[2].collection);
+        assert!(!entries[3].collection);
+        assert_eq!(entries[3].modified, 1704164645);
+        assert_eq!(entries[3].sha256, None);
+    }
+
+    #[test]
+    fn apache_multistatus() {
+        let entries = parse_multistatus(APACHE).unwrap();
+        assert_eq!(entries.len(), 2);
+        let storage = storage("https://nas.local/dav/my%20saves");
+        assert_eq!(storage.key_for_href(&entries[0].href), "");
+        assert!(entries[0].collection);
+        assert_eq!(storage.key_for_href(&entries[1].href), "gba/a[1].sav");
+        assert!(!entries[1].collection);
+        assert_eq!(entries[1].size, 131072);
+        assert_eq!(entries[1].sha256.as_deref(), Some("abc"));
+    }
+
+    #[test]
+    fn urls_for_keys() {
+        let storage = storage("https://nas.local/dav/my%20saves/");
+        assert_eq!(storage.url_for("gba/a[1] b.sav"), "https://nas.local/dav/my%20saves/gba/a%5B1%5D%20b.sav");
+        // what url_for escapes comes back as the same key
+        assert_eq!(storage.key_for_href("/dav/my%20saves/gba/a%5B1%5D%20b.sav"), "gba/a[1] b.sav");
+    }
+}